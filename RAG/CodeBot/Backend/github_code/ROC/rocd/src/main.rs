@@ -39,15 +39,74 @@ fn main() {
             ["PING"] => {
                 json!({"command" : "PING"})
             }
-            ["STORE", key, value] => {
-                json!({"command" : "STORE",
+            ["STORE", key, value, options @ ..] => match store_request(key, value, options) {
+                Ok(request) => request,
+                Err(msg) => {
+                    println!("{}", msg);
+                    continue;
+                }
+            },
+            ["MGET", keys @ ..] if !keys.is_empty() => {
+                json!({"command" : "MGET",
+                "keys" : keys})
+            }
+            ["MSET", pairs @ ..] if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let entries: Vec<Value> = pairs
+                    .chunks(2)
+                    .map(|pair| json!([pair[0], parse_value(pair[1])]))
+                    .collect();
+                json!({"command" : "MSET",
+                "entries" : entries})
+            }
+            ["MDEL", keys @ ..] if !keys.is_empty() => {
+                json!({"command" : "MDEL",
+                "keys" : keys})
+            }
+            ["GETSET", key, value] => {
+                json!({"command" : "GETSET",
                 "key" : key,
-                "value" : value})
+                "value" : parse_value(value)})
+            }
+            ["EXPIRE", key, seconds] => match seconds.parse::<u64>() {
+                Ok(seconds) => json!({"command" : "EXPIRE",
+                "key" : key,
+                "seconds" : seconds}),
+                Err(_) => {
+                    println!("EXPIRE expects a number of seconds!");
+                    continue;
+                }
+            },
+            ["TTL", key] => {
+                json!({"command" : "TTL",
+                "key" : key})
+            }
+            ["PERSIST", key] => {
+                json!({"command" : "PERSIST",
+                "key" : key})
             }
             ["FETCH", key] => {
                 json!({"command" : "FETCH",
                 "key" : key})
             }
+            ["SNAPSHOT"] => {
+                json!({"command" : "SNAPSHOT"})
+            }
+            ["MULTI"] => {
+                json!({"command" : "MULTI"})
+            }
+            ["EXEC"] => {
+                json!({"command" : "EXEC"})
+            }
+            ["DISCARD"] => {
+                json!({"command" : "DISCARD"})
+            }
+            ["WATCH", keys @ ..] if !keys.is_empty() => {
+                json!({"command" : "WATCH",
+                "keys" : keys})
+            }
+            ["UNWATCH"] => {
+                json!({"command" : "UNWATCH"})
+            }
             ["EXIT"] => {
                 break;
             }
@@ -57,17 +116,63 @@ fn main() {
             ["UPDATE", key, value] => {
                 json!({"command" : "UPDATE",
                 "key" : key,
-                "value" : value})
+                "value" : parse_value(value)})
+            }
+            [name @ ("INCR" | "DECR"), key] => {
+                json!({"command" : name,
+                "key" : key})
+            }
+            [name @ ("INCRBY" | "DECRBY"), key, by] => match by.parse::<i64>() {
+                Ok(by) => json!({"command" : name,
+                "key" : key,
+                "by" : by}),
+                Err(_) => {
+                    println!("{} expects an integer!", name);
+                    continue;
+                }
+            },
+            ["CAS", key, expected, value] => {
+                json!({"command" : "CAS",
+                "key" : key,
+                "expected" : parse_value(expected),
+                "value" : parse_value(value)})
             }
             ["DELETE", key] => {
                 json!({"command" : "DELETE",
                 "key" : key})
             }
+            ["GET", "BETWEEN", keys, start, end, options @ ..]
+                if keys.eq_ignore_ascii_case("KEYS") =>
+            {
+                match scan_request(start, end, options) {
+                    Ok(request) => request,
+                    Err(msg) => {
+                        println!("{}", msg);
+                        continue;
+                    }
+                }
+            }
+            ["SCAN", start, end, options @ ..] => match scan_request(start, end, options) {
+                Ok(request) => request,
+                Err(msg) => {
+                    println!("{}", msg);
+                    continue;
+                }
+            },
+            ["GET", "PREFIX", prefix, options @ ..] | ["PREFIX", prefix, options @ ..] => {
+                match prefix_request(prefix, options) {
+                    Ok(request) => request,
+                    Err(msg) => {
+                        println!("{}", msg);
+                        continue;
+                    }
+                }
+            }
             ["GET", "BETWEEN", start, end] => {
                 json!({
                     "command": "RANGE",
-                    "start" : start,
-                    "end" : end
+                    "start" : parse_value(start),
+                    "end" : parse_value(end)
                 })
             }
             _ => {
@@ -79,7 +184,7 @@ fn main() {
         // let's send the json request
         // eprintln!("Gonna write this into the stream: {:?}", request);
         serde_json::to_writer(&mut stream, &request).unwrap();
-        write!(stream, "\n").unwrap();
+        writeln!(stream).unwrap();
         stream.flush().unwrap();
 
         let mut reader = BufReader::new(&stream);
@@ -93,3 +198,123 @@ fn main() {
         }
     }
 }
+
+/// builds a SCAN request from the key bounds and the trailing options
+///
+/// SCAN start end [LIMIT n] [REV] [INCLUSIVE|EXCLUSIVE]
+fn scan_request(start: &str, end: &str, options: &[&str]) -> Result<Value, String> {
+    let mut request = json!({"command" : "SCAN",
+    "start" : start,
+    "end" : end});
+
+    let mut i = 0;
+    while i < options.len() {
+        match options[i].to_uppercase().as_str() {
+            "LIMIT" => {
+                request["limit"] = json!(parse_limit(options.get(i + 1))?);
+                i += 1;
+            }
+            "REV" => request["rev"] = json!(true),
+            "INCLUSIVE" => request["inclusive"] = json!(true),
+            "EXCLUSIVE" => request["inclusive"] = json!(false),
+            other => return Err(format!("Unknown scan option {}", other)),
+        }
+        i += 1;
+    }
+
+    Ok(request)
+}
+
+/// builds a PREFIX request
+///
+/// PREFIX prefix [LIMIT n]
+fn prefix_request(prefix: &str, options: &[&str]) -> Result<Value, String> {
+    let mut request = json!({"command" : "PREFIX",
+    "prefix" : prefix});
+
+    match options {
+        [] => {}
+        [limit, n] if limit.eq_ignore_ascii_case("LIMIT") => {
+            request["limit"] = json!(parse_limit(Some(n))?);
+        }
+        _ => return Err("PREFIX only takes a LIMIT".to_string()),
+    }
+
+    Ok(request)
+}
+
+/// builds a STORE request from the key, the value and the trailing options
+///
+/// STORE key value [EX seconds] [NX|XX]
+fn store_request(key: &str, value: &str, options: &[&str]) -> Result<Value, String> {
+    let mut request = json!({"command" : "STORE",
+    "key" : key,
+    "value" : parse_value(value)});
+
+    let mut i = 0;
+    while i < options.len() {
+        match options[i].to_uppercase().as_str() {
+            "EX" => {
+                let seconds = options
+                    .get(i + 1)
+                    .and_then(|seconds| seconds.parse::<u64>().ok())
+                    .ok_or_else(|| "EX expects a number of seconds!".to_string())?;
+                request["ex"] = json!(seconds);
+                i += 1;
+            }
+            "NX" => request["nx"] = json!(true),
+            "XX" => request["xx"] = json!(true),
+            other => return Err(format!("Unknown store option {}", other)),
+        }
+        i += 1;
+    }
+
+    Ok(request)
+}
+
+fn parse_limit(token: Option<&&str>) -> Result<u64, String> {
+    token
+        .and_then(|n| n.parse::<u64>().ok())
+        .ok_or_else(|| "LIMIT expects a number".to_string())
+}
+
+/// turns a value typed at the prompt into a typed JSON value for the server
+///
+/// 12 -> integer, 2.5 -> float, true/false -> bool, null -> null,
+/// 0x68656c6c6f -> bytes, "quoted" or anything else -> string
+///
+/// Quoted values go out tagged, since the server takes a plain "12" for the integer 12.
+fn parse_value(token: &str) -> Value {
+    if let Ok(i) = token.parse::<i64>() {
+        return json!(i);
+    }
+    if let Ok(f) = token.parse::<f64>() {
+        if f.is_finite() {
+            return json!(f);
+        }
+    }
+    match token {
+        "true" => return json!(true),
+        "false" => return json!(false),
+        "null" => return Value::Null,
+        _ => {}
+    }
+    if let Some(hex) = token.strip_prefix("0x") {
+        if hex.len() % 2 == 0 {
+            let bytes: Option<Vec<u8>> = (0..hex.len())
+                .step_by(2)
+                .map(|i| {
+                    hex.get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect();
+            if let Some(bytes) = bytes {
+                return json!(bytes);
+            }
+        }
+    }
+    match token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        Some(quoted) => json!({ "Str": quoted }),
+        None => json!(token),
+    }
+}
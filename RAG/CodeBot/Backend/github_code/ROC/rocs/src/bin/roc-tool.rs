@@ -0,0 +1,6 @@
+// ROC/rocs/src/bin/roc-tool.rs
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(rocs::tool::run(&args));
+}
@@ -1,161 +1,1632 @@
-use once_cell::sync::Lazy;
-use std::sync::RwLock;
+// ROC/rocs/src/btree.rs
 
-const MIN_DEGREE: usize = 3;
-// Minimum keys that a node must have MIN_DEGREE-1 keys
-// Maximum keys that a node can have is 2*MIN_DEGREE-1 keys
+// A disk backed B+tree
+//
+// The file is made of PAGE_SIZE pages:
+//
+//  pages 0 and 1   -- meta pages (root page, page count, free list, number of entries, LSN)
+//  node pages      -- a bincode encoded `Node`
+//  overflow pages  -- values too big to sit inside a leaf are chained through these
+//  free list pages -- the ids of the pages that can be reused, chained
+//
+// All the entries live in the leaves. Only the pages being worked on are kept in memory by the
+// `Pager`, so the tree can be a lot larger than RAM. The leaves are not linked to each other, a
+// range scan in either direction gets from leaf to leaf through the parents it came down by
+// (see `Cursor`) -- which costs no extra reads, the parents are in memory on the cursor's path.
+//
+// Pages are copy on write: once `flush` has committed a page it is never written again, a change
+// to it goes to a new page (and so does the change to its parent, up to the root). A page the
+// tree stops using is only handed out again after the next commit, as the committed tree may
+// still point to it. A commit writes the new pages, syncs them and only then writes a meta page,
+// to the slot the last commit did not use -- so a crash at any point leaves the last committed
+// tree in the file, and a torn meta page fails its checksum and the other one is used.
+//
+// The same goes for a `View`: taking one makes every page the tree has so far read only, so the
+// view is just the root at the time, and the pages the tree lets go of afterwards are kept until
+// the view is released. A commit takes the page images it has to write in `begin_commit`, the
+// writing and syncing happen without the tree (see `Commit`).
 
-#[derive(Debug, Clone)]
-struct Node {
-    keys: Vec<String>,
-    values: Vec<String>,
-    children: Vec<Option<Box<Node>>>, // recursive allocation
-    is_leaf: bool,
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+type PageId = u64;
+
+const PAGE_SIZE: usize = 4096;
+
+const MAGIC: &[u8; 8] = b"ROCBTREE";
+// version 1 wrote pages in place and could not survive a crash
+const VERSION: u32 = 2;
+
+// the two meta pages, a commit goes to slot (seq % 2)
+const META_SLOTS: u64 = 2;
+// crc32 of everything before it, at the end of the meta page
+const META_CHECKSUM: usize = PAGE_SIZE - 4;
+
+// first byte of every page other than the meta pages
+const PAGE_NODE: u8 = 1;
+const PAGE_OVERFLOW: u8 = 2;
+const PAGE_FREE_LIST: u8 = 3;
+
+// node page = kind byte + u32 length + encoded node
+const NODE_HEADER: usize = 5;
+const NODE_CAPACITY: usize = PAGE_SIZE - NODE_HEADER;
+// a node smaller than this after a delete gets merged with or refilled from a sibling
+const MIN_FILL: usize = NODE_CAPACITY / 4;
+
+// overflow page = kind byte + next overflow page + data
+const OVERFLOW_HEADER: usize = 9;
+const OVERFLOW_DATA: usize = PAGE_SIZE - OVERFLOW_HEADER;
+
+// free list page = kind byte + next free list page + u32 count + page ids
+const FREE_LIST_HEADER: usize = 13;
+const FREE_LIST_IDS: usize = (PAGE_SIZE - FREE_LIST_HEADER) / 8;
+
+/// Keys longer than this are refused, so that every node can always hold a few of them
+const MAX_KEY_SIZE: usize = 512;
+// values longer than this go to overflow pages
+const MAX_INLINE_VALUE: usize = 512;
+
+/// Number of pages the buffer pool keeps in memory (4MB)
+const POOL_CAPACITY: usize = 1024;
+
+// page 0 is a meta page, so no other page can ever point to it
+const NO_PAGE: PageId = 0;
+
+/// Whether the tree can hold key, see MAX_KEY_SIZE
+pub(crate) fn check_key(key: &str) -> Result<(), String> {
+    if key.len() > MAX_KEY_SIZE {
+        return Err(format!("Keys can be at most {} bytes long", MAX_KEY_SIZE));
+    }
+    Ok(())
+}
+
+/// Where the value of an entry lives
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Stored {
+    Inline(Vec<u8>),
+    Overflow { first: PageId, len: u64 },
+}
+
+impl Stored {
+    /// bytes this takes up inside a leaf (roughly)
+    fn footprint(&self) -> usize {
+        match self {
+            Stored::Inline(bytes) => bytes.len() + 12,
+            Stored::Overflow { .. } => 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Node {
+    /// keys[i] holds values[i]
+    ///
+    /// There are no links between the leaves -- with copy on write pages a change to a leaf
+    /// gives it a new page id, so its left neighbour would have to be copied to point to the
+    /// new one, then that one's left neighbour, and so on down to the first leaf.
+    Leaf {
+        keys: Vec<String>,
+        values: Vec<Stored>,
+    },
+    /// children[i] holds the keys in [keys[i-1], keys[i])
+    Internal {
+        keys: Vec<String>,
+        children: Vec<PageId>,
+    },
 }
 
 impl Node {
-    /// defines a new BTreeNode
+    fn empty_leaf() -> Self {
+        Node::Leaf {
+            keys: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    fn encoded_size(&self) -> usize {
+        bincode::serialized_size(self).unwrap_or(u64::MAX) as usize
+    }
+
+    fn fits(&self) -> bool {
+        self.encoded_size() <= NODE_CAPACITY
+    }
+
+    /// Splits an overflowing node in two halves of about the same size in bytes
+    ///
+    /// returns (left, separator, right) -- the separator is the first key of the right half,
+    /// for internal nodes it is moved up and is no longer part of either half
+    fn split(self) -> (Node, String, Node) {
+        match self {
+            Node::Leaf {
+                mut keys,
+                mut values,
+            } => {
+                let sizes: Vec<usize> = keys
+                    .iter()
+                    .zip(&values)
+                    .map(|(k, v)| k.len() + v.footprint() + 8)
+                    .collect();
+                let mid = split_point(&sizes);
+
+                let right_keys = keys.split_off(mid);
+                let right_values = values.split_off(mid);
+                let separator = right_keys[0].clone();
+
+                (
+                    Node::Leaf { keys, values },
+                    separator,
+                    Node::Leaf {
+                        keys: right_keys,
+                        values: right_values,
+                    },
+                )
+            }
+            Node::Internal {
+                mut keys,
+                mut children,
+            } => {
+                let sizes: Vec<usize> = keys.iter().map(|k| k.len() + 16).collect();
+                // keep at least one key on each side
+                let mid = split_point(&sizes).min(keys.len().saturating_sub(2)).max(1);
+
+                let right_keys = keys.split_off(mid + 1);
+                let separator = keys.pop().expect("internal node with a single key split");
+                let right_children = children.split_off(mid + 1);
+
+                (
+                    Node::Internal { keys, children },
+                    separator,
+                    Node::Internal {
+                        keys: right_keys,
+                        children: right_children,
+                    },
+                )
+            }
+        }
+    }
+}
+
+/// index at which the running total of sizes crosses half of the total, never 0 or len
+fn split_point(sizes: &[usize]) -> usize {
+    let total: usize = sizes.iter().sum();
+    let mut running = 0;
+
+    for (i, size) in sizes.iter().enumerate() {
+        running += size;
+        if running * 2 >= total {
+            return (i + 1).min(sizes.len() - 1).max(1);
+        }
+    }
+
+    sizes.len() / 2
+}
+
+/// which child of an internal node holds key
+fn child_index(keys: &[String], key: &str) -> usize {
+    keys.partition_point(|k| k.as_str() <= key)
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u64(page: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(page[at..at + 8].try_into().unwrap())
+}
+
+/// The newest valid meta page, read with read_page -- None if neither is valid
+fn newest_meta(
+    read_page: &mut dyn FnMut(PageId) -> io::Result<Vec<u8>>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut newest: Option<Vec<u8>> = None;
+
+    for slot in 0..META_SLOTS {
+        // the second slot is not there if the first commit never made it
+        let page = match read_page(slot) {
+            Ok(page) => page,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => continue,
+            Err(e) => return Err(e),
+        };
+
+        let checksum = u32::from_le_bytes(page[META_CHECKSUM..].try_into().unwrap());
+        if &page[0..8] != MAGIC || crc32fast::hash(&page[..META_CHECKSUM]) != checksum {
+            continue;
+        }
+        if u32::from_le_bytes(page[8..12].try_into().unwrap()) != VERSION {
+            return Err(corrupt("unsupported B+tree file version"));
+        }
+
+        if newest
+            .as_ref()
+            .is_none_or(|n| read_u64(&page, 12) > read_u64(n, 12))
+        {
+            newest = Some(page);
+        }
+    }
+    Ok(newest)
+}
+
+/// The WAL LSN the tree at path holds on disk, read without opening it for writing
+///
+/// None if there is no tree, 0 if it has no valid meta page -- `BPlusTree::open` starts that one
+/// over.
+pub(crate) fn peek_lsn(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let meta = newest_meta(&mut |id| {
+        let mut page = vec![0u8; PAGE_SIZE];
+        file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        file.read_exact(&mut page)?;
+        Ok(page)
+    })?;
+    Ok(Some(meta.map_or(0, |page| read_u64(&page, 52))))
+}
+
+/// writes page to page id of file, padded to PAGE_SIZE
+fn write_page_to(file: &mut File, id: PageId, page: &[u8]) -> io::Result<()> {
+    debug_assert!(page.len() <= PAGE_SIZE);
+
+    let mut padded = vec![0u8; PAGE_SIZE];
+    padded[..page.len()].copy_from_slice(page);
+    file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+    file.write_all(&padded)
+}
+
+fn node_page(node: &Node) -> io::Result<Vec<u8>> {
+    let encoded = bincode::serialize(node).map_err(|e| corrupt(&e.to_string()))?;
+    if encoded.len() > NODE_CAPACITY {
+        return Err(corrupt("node does not fit into a page"));
+    }
+
+    let mut page = Vec::with_capacity(NODE_HEADER + encoded.len());
+    page.push(PAGE_NODE);
+    page.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    page.extend_from_slice(&encoded);
+    Ok(page)
+}
+
+/// the ids of free pages, chained to the list page next
+fn free_list_page(ids: &[PageId], next: PageId) -> Vec<u8> {
+    let mut page = Vec::with_capacity(FREE_LIST_HEADER + ids.len() * 8);
+    page.push(PAGE_FREE_LIST);
+    page.extend_from_slice(&next.to_le_bytes());
+    page.extend_from_slice(&(ids.len() as u32).to_le_bytes());
+    for id in ids {
+        page.extend_from_slice(&id.to_le_bytes());
+    }
+    page
+}
+
+struct Frame {
+    node: Node,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// The buffer pool and the bookkeeping of which pages can be written
+///
+/// Keeps up to `capacity` decoded node pages in memory, writes dirty ones back when they are
+/// evicted (least recently used first) or on `flush`. Overflow and free list pages are not
+/// cached, they go straight to the file.
+struct Pager {
+    file: File,
+    frames: HashMap<PageId, Frame>,
+    capacity: usize,
+    tick: u64,
+    page_count: u64,
+    /// pages nothing points to, not even the committed tree -- these can be handed out
+    free: Vec<PageId>,
+    /// pages handed out since the last commit or view, the only ones that can be written in place
+    fresh: HashSet<PageId>,
+    /// pages the tree stopped using since the last commit, with the generation they were let go
+    /// of in -- the committed tree and the views taken before may still use them
+    retired: Vec<(PageId, u64)>,
+    /// pages that are free on disk but may still be used by a view
+    held: Vec<(PageId, u64)>,
+    /// pages holding the committed free list
+    free_list_pages: Vec<PageId>,
+    /// goes up with every view
+    generation: u64,
+    /// generation -> number of views taken in it that are still open
+    views: BTreeMap<u64, usize>,
+}
+
+impl Pager {
+    fn read_page(&mut self, id: PageId) -> io::Result<Vec<u8>> {
+        let mut page = vec![0u8; PAGE_SIZE];
+        self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        self.file.read_exact(&mut page)?;
+        Ok(page)
+    }
+
+    fn write_page(&mut self, id: PageId, page: &[u8]) -> io::Result<()> {
+        write_page_to(&mut self.file, id, page)
+    }
+
+    fn write_node(&mut self, id: PageId, node: &Node) -> io::Result<()> {
+        let page = node_page(node)?;
+        self.write_page(id, &page)
+    }
+
+    /// returns a copy of the node on page id
+    fn node(&mut self, id: PageId) -> io::Result<Node> {
+        self.tick += 1;
+        if let Some(frame) = self.frames.get_mut(&id) {
+            frame.last_used = self.tick;
+            return Ok(frame.node.clone());
+        }
+
+        let page = self.read_page(id)?;
+        if page[0] != PAGE_NODE {
+            return Err(corrupt("expected a node page"));
+        }
+        let len = u32::from_le_bytes(page[1..5].try_into().unwrap()) as usize;
+        if len > NODE_CAPACITY {
+            return Err(corrupt("node page with an invalid length"));
+        }
+        let node: Node = bincode::deserialize(&page[NODE_HEADER..NODE_HEADER + len])
+            .map_err(|e| corrupt(&e.to_string()))?;
+
+        self.cache(id, node.clone(), false)?;
+        Ok(node)
+    }
+
+    /// replaces the node on page id, it reaches the file on eviction or flush
+    ///
+    /// Only fresh pages can be written, go through `writable` first.
+    fn put_node(&mut self, id: PageId, node: Node) -> io::Result<()> {
+        debug_assert!(self.fresh.contains(&id), "page {} is committed", id);
+
+        self.tick += 1;
+        if let Some(frame) = self.frames.get_mut(&id) {
+            frame.node = node;
+            frame.dirty = true;
+            frame.last_used = self.tick;
+            return Ok(());
+        }
+
+        self.cache(id, node, true)
+    }
+
+    fn cache(&mut self, id: PageId, node: Node, dirty: bool) -> io::Result<()> {
+        if self.frames.len() >= self.capacity {
+            self.evict()?;
+        }
+
+        self.frames.insert(
+            id,
+            Frame {
+                node,
+                dirty,
+                last_used: self.tick,
+            },
+        );
+        Ok(())
+    }
+
+    fn evict(&mut self) -> io::Result<()> {
+        let victim = self
+            .frames
+            .iter()
+            .min_by_key(|(_, frame)| frame.last_used)
+            .map(|(id, _)| *id);
+
+        if let Some(id) = victim {
+            let frame = self.frames.remove(&id).unwrap();
+            // a page is only ever changed while it is fresh, so this writes what the committed
+            // tree and the views expect, if they use the page at all
+            if frame.dirty {
+                self.write_node(id, &frame.node)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// hands out a page -- a free one if there is any, a new one at the end of the file otherwise
+    fn allocate(&mut self) -> PageId {
+        let id = self.free.pop().unwrap_or_else(|| {
+            self.page_count += 1;
+            self.page_count - 1
+        });
+        // whatever was cached for it is of no use to anyone anymore
+        self.frames.remove(&id);
+        self.fresh.insert(id);
+        id
+    }
+
+    /// returns a page the node on page id can be written to -- page id itself if it was handed
+    /// out since the last commit, a copy otherwise
+    fn writable(&mut self, id: PageId) -> PageId {
+        if self.fresh.contains(&id) {
+            return id;
+        }
+
+        self.release(id);
+        self.allocate()
+    }
+
+    /// gives back a page the tree does not use anymore
+    fn release(&mut self, id: PageId) {
+        if self.fresh.remove(&id) {
+            self.frames.remove(&id);
+            self.free.push(id);
+        } else {
+            // a page from before the last commit or view has to wait for the next commit and
+            // for the views -- its frame stays, it may not have reached the file yet
+            self.retired.push((id, self.generation));
+        }
+    }
+
+    /// whether an open view may use a page let go of in generation
+    fn used_by_view(&self, generation: u64) -> bool {
+        self.views
+            .keys()
+            .next()
+            .is_some_and(|oldest| *oldest < generation)
+    }
+
+    /// hands out the pages the committed tree does not use, unless a view may still use them
+    fn hand_back(&mut self, pages: Vec<(PageId, u64)>) {
+        for (id, generation) in pages {
+            if self.used_by_view(generation) {
+                self.held.push((id, generation));
+            } else {
+                self.free.push(id);
+            }
+        }
+    }
+}
+
+/// A version of the tree frozen by `BPlusTree::view`, release it with `release_view`
+pub(crate) struct View {
+    root: PageId,
+    generation: u64,
+}
+
+/// A commit between `BPlusTree::begin_commit` and `end_commit`
+///
+/// It holds the images of the pages to write and writes them through a file handle of its own,
+/// so the tree can go on being read and changed meanwhile -- nothing it writes is a page the
+/// tree can change or hand out before `end_commit`.
+pub(crate) struct Commit {
+    path: PathBuf,
+    pages: Vec<(PageId, Vec<u8>)>,
+    meta: Vec<u8>,
+    seq: u64,
+    lsn: u64,
+    retired: Vec<(PageId, u64)>,
+    list_pages: Vec<PageId>,
+}
+
+impl Commit {
+    /// Writes the new pages, syncs them, then writes and syncs the meta page that points to them
+    pub(crate) fn write(&self) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+
+        for (id, page) in &self.pages {
+            write_page_to(&mut file, *id, page)?;
+        }
+        file.sync_data()?;
+
+        write_page_to(&mut file, self.seq % META_SLOTS, &self.meta)?;
+        file.sync_data()
+    }
+}
+
+/// A B+tree of String keys and byte values stored in a single file
+///
+/// Writes are buffered in the pager, call `flush` to commit them.
+pub(crate) struct BPlusTree {
+    path: PathBuf,
+    pager: Pager,
+    root: PageId,
+    len: u64,
+    /// sequence number of the last commit, it went to meta slot seq % 2
+    seq: u64,
+    /// the WAL LSN the tree holds, as told by `set_lsn` -- it goes to the meta page on commit
+    lsn: u64,
+    /// the LSN of the last commit
+    committed_lsn: u64,
+    /// a commit is between `begin_commit` and `end_commit`
+    committing: bool,
+}
+
+impl BPlusTree {
+    /// Opens the tree stored at path, creating an empty one if the file does not exist
     ///
     /// Parameters:
     ///
-    /// > leaf: bool
-    fn new(leaf: bool) -> Self {
-        Node {
-            keys: Vec::new(),
-            values: Vec::new(),
-            children: Vec::new(),
-            leaf,
+    /// > path: AsRef<Path>
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.as_ref())?;
+
+        let len = file.metadata()?.len();
+
+        let mut tree = BPlusTree {
+            path: path.as_ref().to_path_buf(),
+            pager: Pager {
+                file,
+                frames: HashMap::new(),
+                capacity: POOL_CAPACITY,
+                tick: 0,
+                page_count: META_SLOTS,
+                free: Vec::new(),
+                fresh: HashSet::new(),
+                retired: Vec::new(),
+                held: Vec::new(),
+                free_list_pages: Vec::new(),
+                generation: 0,
+                views: BTreeMap::new(),
+            },
+            root: NO_PAGE,
+            len: 0,
+            seq: 0,
+            lsn: 0,
+            committed_lsn: 0,
+            committing: false,
+        };
+
+        if len == 0 {
+            tree.init()?;
+        } else if !tree.read_meta()? {
+            let page = tree.pager.read_page(0).unwrap_or_default();
+            if page.starts_with(MAGIC) && page[8..12] == 1u32.to_le_bytes() {
+                // a version 1 file, it may have been torn by a crash and cannot be trusted --
+                // start over and let the snapshot and the WAL fill it again
+                eprintln!(
+                    "{:?} was written by an older version of ROC, rebuilding it",
+                    path.as_ref()
+                );
+                tree.clear()?;
+            } else if len <= 3 * PAGE_SIZE as u64 && page.iter().all(|b| *b == 0) {
+                // a crash while the file was being set up, before its first commit
+                tree.clear()?;
+            } else {
+                return Err(corrupt("not a ROC B+tree file"));
+            }
+        }
+
+        Ok(tree)
+    }
+
+    /// sets up an empty tree -- an empty leaf as the root -- and commits it
+    fn init(&mut self) -> io::Result<()> {
+        let pager = &mut self.pager;
+        pager.frames.clear();
+        pager.page_count = META_SLOTS;
+        pager.free.clear();
+        pager.fresh.clear();
+        pager.retired.clear();
+        pager.held.clear();
+        pager.free_list_pages.clear();
+        self.len = 0;
+        self.seq = 0;
+        self.lsn = 0;
+
+        self.root = self.pager.allocate();
+        self.pager.put_node(self.root, Node::empty_leaf())?;
+        self.flush()
+    }
+
+    /// Loads the newest valid meta page and the free list it points to
+    ///
+    /// returns false if neither meta page is valid
+    fn read_meta(&mut self) -> io::Result<bool> {
+        let page = match newest_meta(&mut |id| self.pager.read_page(id))? {
+            Some(page) => page,
+            None => return Ok(false),
+        };
+
+        self.seq = read_u64(&page, 12);
+        self.root = read_u64(&page, 20);
+        self.pager.page_count = read_u64(&page, 28);
+        self.len = read_u64(&page, 44);
+        self.lsn = read_u64(&page, 52);
+        self.committed_lsn = self.lsn;
+
+        let mut id = read_u64(&page, 36);
+        while id != NO_PAGE {
+            let list = self.pager.read_page(id)?;
+            if list[0] != PAGE_FREE_LIST {
+                return Err(corrupt("expected a free list page"));
+            }
+            let count = u32::from_le_bytes(list[9..13].try_into().unwrap()) as usize;
+            if count > FREE_LIST_IDS {
+                return Err(corrupt("free list page with an invalid count"));
+            }
+
+            self.pager.free_list_pages.push(id);
+            self.pager
+                .free
+                .extend((0..count).map(|i| read_u64(&list, FREE_LIST_HEADER + i * 8)));
+            id = read_u64(&list, 1);
+        }
+        Ok(true)
+    }
+
+    fn meta_page(&self, seq: u64, free_list: PageId) -> Vec<u8> {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        page.extend_from_slice(MAGIC);
+        page.extend_from_slice(&VERSION.to_le_bytes());
+        page.extend_from_slice(&seq.to_le_bytes());
+        page.extend_from_slice(&self.root.to_le_bytes());
+        page.extend_from_slice(&self.pager.page_count.to_le_bytes());
+        page.extend_from_slice(&free_list.to_le_bytes());
+        page.extend_from_slice(&self.len.to_le_bytes());
+        page.extend_from_slice(&self.lsn.to_le_bytes());
+        page.resize(META_CHECKSUM, 0);
+        let checksum = crc32fast::hash(&page);
+        page.extend_from_slice(&checksum.to_le_bytes());
+        page
+    }
+
+    /// Commits every change so far, see `begin_commit`
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        let commit = self.begin_commit()?;
+        let written = commit.write();
+        self.end_commit(commit, written)
+    }
+
+    /// Starts a commit of every change so far
+    ///
+    /// Only takes the images of the pages to write, `Commit::write` writes them and the tree can
+    /// be used meanwhile. Hand the outcome to `end_commit`.
+    pub(crate) fn begin_commit(&mut self) -> io::Result<Commit> {
+        if self.committing {
+            return Err(io::Error::other("a commit is already running"));
+        }
+
+        let mut pages = Vec::new();
+        for (id, frame) in &self.pager.frames {
+            if frame.dirty {
+                pages.push((*id, node_page(&frame.node)?));
+            }
+        }
+
+        // from here on every change goes to new pages, these stay as they are
+        self.pager.fresh.clear();
+
+        // what the new tree does not use -- on disk, that is: in memory the pages it stopped
+        // using can only be handed out once the commit is done
+        let retired = std::mem::take(&mut self.pager.retired);
+        let mut free = self.pager.free.clone();
+        free.extend(retired.iter().chain(&self.pager.held).map(|(id, _)| *id));
+        free.extend(&self.pager.free_list_pages);
+
+        // the free list goes to new pages at the end of the file, any other page may belong to
+        // one of the two trees the meta pages point to
+        let list_pages: Vec<PageId> = (0..free.len().div_ceil(FREE_LIST_IDS) as u64)
+            .map(|i| self.pager.page_count + i)
+            .collect();
+        self.pager.page_count += list_pages.len() as u64;
+        for (i, ids) in free.chunks(FREE_LIST_IDS).enumerate() {
+            let next = list_pages.get(i + 1).copied().unwrap_or(NO_PAGE);
+            pages.push((list_pages[i], free_list_page(ids, next)));
+        }
+
+        let seq = self.seq + 1;
+        let first = list_pages.first().copied().unwrap_or(NO_PAGE);
+        self.committing = true;
+        Ok(Commit {
+            path: self.path.clone(),
+            pages,
+            meta: self.meta_page(seq, first),
+            seq,
+            lsn: self.lsn,
+            retired,
+            list_pages,
+        })
+    }
+
+    /// Finishes a commit with the outcome of `Commit::write`, which it passes on
+    pub(crate) fn end_commit(&mut self, commit: Commit, written: io::Result<()>) -> io::Result<()> {
+        self.committing = false;
+
+        if let Err(e) = written {
+            // the new meta page may or may not be on disk -- keep away from every page either
+            // tree uses, the next commit retries the same slot so the old meta page stays intact
+            let generation = self.pager.generation;
+            self.pager.retired.extend(commit.retired);
+            self.pager
+                .retired
+                .extend(commit.list_pages.iter().map(|id| (*id, generation)));
+            return Err(e);
+        }
+
+        self.seq = commit.seq;
+        self.committed_lsn = commit.lsn;
+        // nothing written can have changed since, it is all read only
+        for (id, _) in &commit.pages {
+            if let Some(frame) = self.pager.frames.get_mut(id) {
+                frame.dirty = false;
+            }
+        }
+        let old_list = std::mem::replace(&mut self.pager.free_list_pages, commit.list_pages);
+        self.pager.free.extend(old_list);
+        self.pager.hand_back(commit.retired);
+        Ok(())
+    }
+
+    /// Takes a frozen version of the tree, it stays as it is while the tree changes
+    ///
+    /// This is cheap -- the pages are not copied, the tree just writes every change to new pages
+    /// from now on, like after a commit, and keeps the pages it lets go of until the view is
+    /// released.
+    pub(crate) fn view(&mut self) -> View {
+        self.pager.fresh.clear();
+
+        let generation = self.pager.generation;
+        self.pager.generation += 1;
+        *self.pager.views.entry(generation).or_default() += 1;
+        View {
+            root: self.root,
+            generation,
+        }
+    }
+
+    /// Lets go of a view, the pages only it used can be handed out again
+    pub(crate) fn release_view(&mut self, view: View) {
+        if let Some(count) = self.pager.views.get_mut(&view.generation) {
+            *count -= 1;
+            if *count == 0 {
+                self.pager.views.remove(&view.generation);
+            }
+        }
+
+        let held = std::mem::take(&mut self.pager.held);
+        self.pager.hand_back(held);
+    }
+
+    /// A cursor over all the entries of a view, walk it with `Cursor::step`
+    pub(crate) fn view_cursor(&mut self, view: &View) -> io::Result<Cursor> {
+        self.seek(view.root, Bound::Unbounded, Bound::Unbounded, false)
+    }
+
+    /// Records that the tree holds the WAL up to lsn, it is committed with the next `flush`
+    pub(crate) fn set_lsn(&mut self, lsn: u64) {
+        self.lsn = lsn;
+    }
+
+    /// The LSN the last commit holds the WAL up to, 0 if it was never set
+    pub(crate) fn committed_lsn(&self) -> u64 {
+        self.committed_lsn
+    }
+
+    /// Drops every entry and shrinks the file back to an empty tree, open views are gone as well
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        self.pager.file.set_len(0)?;
+        self.init()
+    }
+
+    /// Looks up the value of a key
+    ///
+    /// Parameters:
+    ///
+    /// > key: &str
+    pub(crate) fn get(&mut self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut page = self.root;
+
+        loop {
+            match self.pager.node(page)? {
+                Node::Internal { keys, children } => page = children[child_index(&keys, key)],
+                Node::Leaf { keys, values } => {
+                    return match keys.binary_search_by(|k| k.as_str().cmp(key)) {
+                        Ok(i) => self.load_value(&values[i]).map(Some),
+                        Err(_) => Ok(None),
+                    };
+                }
+            }
         }
     }
 
-    /// Inserts a key-value pair into a node that is not full.
-    /// For leaf nodes, it finds the right position (maintaining order) and inserts.
-    /// For internal nodes, it finds the correct child and recursively calls insertion.
+    /// Inserts a key-value pair, returns the previous value of the key if there was one
     ///
     /// Parameters:
     ///
     /// > key: String
-    /// > value: String
-    fn insert_not_full(&mut self, key: String, val: String) {
-        // compare the Node values for insertion ..
-        // we need a variable to determine the correct position of insertion ..
-        let mut i = self.keys.len();
+    /// > value: Vec<u8>
+    pub(crate) fn insert(&mut self, key: String, value: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        check_key(&key).map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+
+        let stored = self.store_value(value)?;
+        let (old, root, split) = self.insert_into(self.root, key, stored)?;
+        self.root = root;
+
+        if let Some((separator, right)) = split {
+            // the root was split -- the tree grows by one level
+            let new_root = self.pager.allocate();
+            self.pager.put_node(
+                new_root,
+                Node::Internal {
+                    keys: vec![separator],
+                    children: vec![self.root, right],
+                },
+            )?;
+            self.root = new_root;
+        }
+
+        match old {
+            Some(old) => {
+                let value = self.load_value(&old)?;
+                self.free_value(old)?;
+                Ok(Some(value))
+            }
+            None => {
+                self.len += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Inserts into the subtree at page
+    ///
+    /// returns the replaced value, the page the subtree now starts at and, if it had to be
+    /// split, the separator and the page of the new right sibling that the parent has to take in
+    #[allow(clippy::type_complexity)]
+    fn insert_into(
+        &mut self,
+        page: PageId,
+        key: String,
+        stored: Stored,
+    ) -> io::Result<(Option<Stored>, PageId, Option<(String, PageId)>)> {
+        let mut node = self.pager.node(page)?;
 
-        // now that you have the length ... you gotta find the position where you have to insert
-        // recursively go down on it ...
-        if self.is_leaf {
-            // incase it is a leaf
+        let old = match &mut node {
+            Node::Leaf { keys, values } => match keys.binary_search(&key) {
+                Ok(i) => Some(std::mem::replace(&mut values[i], stored)),
+                Err(i) => {
+                    keys.insert(i, key);
+                    values.insert(i, stored);
+                    None
+                }
+            },
+            Node::Internal { keys, children } => {
+                let i = child_index(keys, &key);
+                let (old, child, split) = self.insert_into(children[i], key, stored)?;
 
-            while i > 0 && key < self.keys[i - 1] {
-                // keys are in order ... so we just need to keep moving back to find the position
-                // in the keys vector ....
-                i -= 1;
+                if child == children[i] && split.is_none() {
+                    // nothing changed in this node
+                    return Ok((old, page, None));
+                }
+                children[i] = child;
+                if let Some((separator, right)) = split {
+                    keys.insert(i, separator);
+                    children.insert(i + 1, right);
+                }
+                old
             }
+        };
 
-            // now that we have found the position .. let's just insert them into the current node
-            // at the proper position
-            self.keys.insert(i, key); // inserts them at the ith index
-            self.values.insert(i, val);
+        let page = self.pager.writable(page);
+        if node.fits() {
+            self.pager.put_node(page, node)?;
+            return Ok((old, page, None));
+        }
 
-            // but what is it is a full leaf node ???
-        } else {
-            // node is not a leaf
-            while i > 0 && key < self.keys[i - 1] {
-                i -= 1;
+        let right_page = self.pager.allocate();
+        let (left, separator, right) = node.split();
+
+        self.pager.put_node(page, left)?;
+        self.pager.put_node(right_page, right)?;
+        Ok((old, page, Some((separator, right_page))))
+    }
+
+    /// Removes a key, returns its value if it was there
+    ///
+    /// Parameters:
+    ///
+    /// > key: &str
+    pub(crate) fn delete(&mut self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let removed = match self.delete_from(self.root, key)? {
+            Some((stored, root)) => {
+                self.root = root;
+                stored
             }
+            None => return Ok(None),
+        };
+        self.len -= 1;
+
+        // a root with a single child is not needed anymore -- the tree shrinks by one level
+        if let Node::Internal { keys, children } = self.pager.node(self.root)? {
+            if keys.is_empty() {
+                self.pager.release(self.root);
+                self.root = children[0];
+            }
+        }
 
-            i += 1; // We want the child right after the key that is less than the new key
-            if self.children[i].as_ref().unwrap().keys.len() == 2 * MIN_DEGREE - 1 {
-                self.split_child(i);
-                // after splitting .. gotta decide which child to descend into
-                if key > self.keys[i] {
-                    i += 1;
+        let value = self.load_value(&removed)?;
+        self.free_value(removed)?;
+        Ok(Some(value))
+    }
+
+    /// Removes key from the subtree at page, returns its value and the page the subtree now
+    /// starts at
+    fn delete_from(&mut self, page: PageId, key: &str) -> io::Result<Option<(Stored, PageId)>> {
+        let mut node = self.pager.node(page)?;
+
+        let removed = match &mut node {
+            Node::Leaf { keys, values } => match keys.binary_search_by(|k| k.as_str().cmp(key)) {
+                Ok(i) => {
+                    keys.remove(i);
+                    values.remove(i)
+                }
+                Err(_) => return Ok(None),
+            },
+            Node::Internal { keys, children } => {
+                let i = child_index(keys, key);
+                let (removed, child) = match self.delete_from(children[i], key)? {
+                    Some(removed) => removed,
+                    None => return Ok(None),
+                };
+
+                let underfull = self.pager.node(child)?.encoded_size() < MIN_FILL;
+                if child == children[i] && !underfull {
+                    // nothing changed in this node
+                    return Ok(Some((removed, page)));
+                }
+                children[i] = child;
+                if underfull {
+                    self.rebalance(keys, children, i)?;
                 }
+                removed
             }
+        };
 
-            // recursively insert into the required child --
-            self.children[i].as_mut().unwrap().insert_not_full(key, val);
+        let page = self.pager.writable(page);
+        self.pager.put_node(page, node)?;
+        Ok(Some((removed, page)))
+    }
+
+    /// Fixes up the underfull child i of an internal node
+    ///
+    /// The child is merged with a sibling if both fit into one page, otherwise the entries of
+    /// the two are spread evenly over both of them.
+    fn rebalance(
+        &mut self,
+        keys: &mut Vec<String>,
+        children: &mut Vec<PageId>,
+        i: usize,
+    ) -> io::Result<()> {
+        if children.len() < 2 {
+            return Ok(());
+        }
+
+        // prefer the left sibling, the leftmost child has only a right one
+        let (l, r) = if i > 0 { (i - 1, i) } else { (i, i + 1) };
+        let (left_page, right_page) = (children[l], children[r]);
+
+        let merged = match (self.pager.node(left_page)?, self.pager.node(right_page)?) {
+            (
+                Node::Leaf {
+                    keys: mut left_keys,
+                    values: mut left_values,
+                },
+                Node::Leaf {
+                    keys: right_keys,
+                    values: right_values,
+                },
+            ) => {
+                left_keys.extend(right_keys);
+                left_values.extend(right_values);
+                Node::Leaf {
+                    keys: left_keys,
+                    values: left_values,
+                }
+            }
+            (
+                Node::Internal {
+                    keys: mut left_keys,
+                    children: mut left_children,
+                },
+                Node::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            ) => {
+                // the separator comes down between the two halves
+                left_keys.push(keys[l].clone());
+                left_keys.extend(right_keys);
+                left_children.extend(right_children);
+                Node::Internal {
+                    keys: left_keys,
+                    children: left_children,
+                }
+            }
+            _ => return Err(corrupt("siblings on different levels")),
+        };
+
+        if merged.fits() {
+            let left_page = self.pager.writable(left_page);
+            self.pager.put_node(left_page, merged)?;
+            self.pager.release(right_page);
+            children[l] = left_page;
+            keys.remove(l);
+            children.remove(r);
+            return Ok(());
         }
+
+        let (left, separator, right) = merged.split();
+        let (left_page, right_page) = (
+            self.pager.writable(left_page),
+            self.pager.writable(right_page),
+        );
+
+        self.pager.put_node(left_page, left)?;
+        self.pager.put_node(right_page, right)?;
+        children[l] = left_page;
+        children[r] = right_page;
+        keys[l] = separator;
+        Ok(())
     }
 
-    /// Splits a full child node.
-    /// Promotes the median key from the full child to the current node
+    /// Iterates over the entries with keys within the bounds, in key order
     ///
     /// Parameters:
     ///
-    /// > i: usize (index of the child to split)
-    fn split_child(&mut self, i: usize) {
-        // y is one of the children -- we gotta split this child
-        let mut y = self.children[i]
-            .take() // takes the value of the option and leaves a None in its place
-            .unwrap();
+    /// > start: Bound<&str>
+    /// > end: Bound<&str>
+    /// > rev: bool (from end down to start)
+    pub(crate) fn range(
+        &mut self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        rev: bool,
+    ) -> io::Result<Range<'_>> {
+        let cursor = self.seek(self.root, start, end, rev)?;
+        Ok(Range { tree: self, cursor })
+    }
+
+    /// A cursor at the start of a key range of the tree under root, or at its end with rev
+    fn seek(
+        &mut self,
+        root: PageId,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        rev: bool,
+    ) -> io::Result<Cursor> {
+        let (from, stop) = if rev { (end, start) } else { (start, end) };
+
+        // descend to the leaf that would hold the key to start from, remembering the way down
+        let mut path = Vec::new();
+        let mut page = root;
+        let leaf = loop {
+            match self.pager.node(page)? {
+                Node::Internal { keys, children } => {
+                    let i = match from {
+                        Bound::Included(key) | Bound::Excluded(key) => child_index(&keys, key),
+                        Bound::Unbounded if rev => children.len() - 1,
+                        Bound::Unbounded => 0,
+                    };
+                    page = children[i];
+                    path.push((children, i));
+                }
+                leaf => break leaf,
+            }
+        };
+
+        // forward the position of the first key to take, backwards the one after it
+        let pos = match (&leaf, from) {
+            (Node::Leaf { keys, .. }, Bound::Included(key)) if rev => {
+                keys.partition_point(|k| k.as_str() <= key)
+            }
+            (Node::Leaf { keys, .. }, Bound::Excluded(key)) if rev => {
+                keys.partition_point(|k| k.as_str() < key)
+            }
+            (Node::Leaf { keys, .. }, Bound::Unbounded) if rev => keys.len(),
+            (Node::Leaf { keys, .. }, Bound::Included(key)) => {
+                keys.partition_point(|k| k.as_str() < key)
+            }
+            (Node::Leaf { keys, .. }, Bound::Excluded(key)) => {
+                keys.partition_point(|k| k.as_str() <= key)
+            }
+            _ => 0,
+        };
+
+        Ok(Cursor {
+            path,
+            leaf: Some(leaf),
+            pos,
+            stop: match stop {
+                Bound::Included(key) => Bound::Included(key.to_string()),
+                Bound::Excluded(key) => Bound::Excluded(key.to_string()),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+            rev,
+        })
+    }
+
+    fn store_value(&mut self, value: Vec<u8>) -> io::Result<Stored> {
+        if value.len() <= MAX_INLINE_VALUE {
+            return Ok(Stored::Inline(value));
+        }
+
+        // write the chunks back to front so that each page knows the page after it
+        let mut next = NO_PAGE;
+        for chunk in value.chunks(OVERFLOW_DATA).rev() {
+            let id = self.pager.allocate();
+            let mut page = Vec::with_capacity(OVERFLOW_HEADER + chunk.len());
+            page.push(PAGE_OVERFLOW);
+            page.extend_from_slice(&next.to_le_bytes());
+            page.extend_from_slice(chunk);
+            self.pager.write_page(id, &page)?;
+            next = id;
+        }
+
+        Ok(Stored::Overflow {
+            first: next,
+            len: value.len() as u64,
+        })
+    }
+
+    fn load_value(&mut self, stored: &Stored) -> io::Result<Vec<u8>> {
+        match stored {
+            Stored::Inline(bytes) => Ok(bytes.clone()),
+            Stored::Overflow { first, len } => {
+                let len = *len as usize;
+                let mut value = Vec::with_capacity(len);
+                let mut id = *first;
+
+                while value.len() < len {
+                    if id == NO_PAGE {
+                        return Err(corrupt("overflow chain ends early"));
+                    }
+                    let page = self.pager.read_page(id)?;
+                    if page[0] != PAGE_OVERFLOW {
+                        return Err(corrupt("expected an overflow page"));
+                    }
+                    let take = (len - value.len()).min(OVERFLOW_DATA);
+                    value.extend_from_slice(&page[OVERFLOW_HEADER..OVERFLOW_HEADER + take]);
+                    id = read_u64(&page, 1);
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    fn free_value(&mut self, stored: Stored) -> io::Result<()> {
+        if let Stored::Overflow { first, .. } = stored {
+            let mut id = first;
+            while id != NO_PAGE {
+                let page = self.pager.read_page(id)?;
+                if page[0] != PAGE_OVERFLOW {
+                    return Err(corrupt("expected an overflow page"));
+                }
+                self.pager.release(id);
+                id = read_u64(&page, 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BPlusTree {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Failed to flush the B+tree: {}", e);
+        }
+    }
+}
+
+/// A position in a key range of the tree
+///
+/// Keeps the internal nodes on the way down to the current leaf, with the child it is in for
+/// each, and climbs back up through them when a leaf is done -- that is how it gets to the next
+/// leaf in either direction, there are no links between them (see `Node::Leaf`). It does not
+/// borrow the tree, every step takes it -- so a cursor over a view can be kept while the tree is
+/// used otherwise.
+pub(crate) struct Cursor {
+    path: Vec<(Vec<PageId>, usize)>,
+    leaf: Option<Node>,
+    /// forward the next key to take, backwards the one after it
+    pos: usize,
+    /// the bound the cursor walks towards, the end of the range or with rev its start
+    stop: Bound<String>,
+    rev: bool,
+}
 
-        let mut z = Box::new(Node::new(y.is_leaf)); // create a new node at the same level as y
+impl Cursor {
+    /// The next entry in the range, None at the end of it
+    pub(crate) fn step(&mut self, tree: &mut BPlusTree) -> Option<io::Result<(String, Vec<u8>)>> {
+        loop {
+            let (keys, values) = match &self.leaf {
+                Some(Node::Leaf { keys, values }) => (keys, values),
+                _ => return None,
+            };
 
-        // y gets to keep the first MIN_DEGREE elements and the rest go to z ..
-        z.keys = y.keys.split_off(MIN_DEGREE); // splits at the given index! -- split_off is a method of vec![] ...
-        z.values = y.values.split_off(MIN_DEGREE);
+            let at = match self.rev {
+                true => self.pos.checked_sub(1),
+                false => Some(self.pos).filter(|pos| *pos < keys.len()),
+            };
+            if let Some(at) = at {
+                let key = &keys[at];
+                let done = match (&self.stop, self.rev) {
+                    (Bound::Included(stop), false) => key > stop,
+                    (Bound::Excluded(stop), false) => key >= stop,
+                    (Bound::Included(stop), true) => key < stop,
+                    (Bound::Excluded(stop), true) => key <= stop,
+                    (Bound::Unbounded, _) => false,
+                };
+                if done {
+                    self.leaf = None;
+                    return None;
+                }
+
+                let key = key.clone();
+                let stored = values[at].clone();
+                self.pos = if self.rev { at } else { at + 1 };
+                return Some(tree.load_value(&stored).map(|value| (key, value)));
+            }
 
-        if !y.is_leaf {
-            // if y is not a leaf .. the children should be split too .. common sense!
-            z.children = y.children.split_off(MIN_DEGREE);
-            // y will have one less children than what's required ...
+            // done with this leaf, move on to the next one
+            match self.next_leaf(tree) {
+                Ok(leaf) => {
+                    self.pos = match &leaf {
+                        Some(Node::Leaf { keys, .. }) if self.rev => keys.len(),
+                        _ => 0,
+                    };
+                    self.leaf = leaf;
+                }
+                Err(e) => {
+                    self.leaf = None;
+                    return Some(Err(e));
+                }
+            }
         }
+    }
+
+    /// the leaf after the current one (before it with rev), None at the end of the tree
+    fn next_leaf(&mut self, tree: &mut BPlusTree) -> io::Result<Option<Node>> {
+        let rev = self.rev;
 
-        self.children.insert(i + 1, Some(z));
-        // remove the last element from y and insert into the parent node at index i
-        // median key in y is promoted to parent
-        self.keys.insert(i, y.keys.pop().unwrap());
-        self.values.insert(i, y.values.pop().unwrap());
+        // climb up to the first node with a child left to visit
+        let mut page = loop {
+            let Some((children, i)) = self.path.last_mut() else {
+                return Ok(None);
+            };
+            let next = match rev {
+                true => i.checked_sub(1),
+                false => Some(*i + 1).filter(|next| *next < children.len()),
+            };
+            match next {
+                Some(next) => {
+                    *i = next;
+                    break children[next];
+                }
+                None => {
+                    self.path.pop();
+                }
+            }
+        };
 
-        // set back the child node to the newly modified node
-        self.children[i] = Some(y);
+        // and down its nearest side
+        loop {
+            match tree.pager.node(page)? {
+                Node::Internal { children, .. } => {
+                    let i = if rev { children.len() - 1 } else { 0 };
+                    page = children[i];
+                    self.path.push((children, i));
+                }
+                leaf => return Ok(Some(leaf)),
+            }
+        }
     }
 }
 
-struct Btree {
-    root: Option<Box<Node>>,
+/// Iterator over a key range of the tree
+pub(crate) struct Range<'a> {
+    tree: &'a mut BPlusTree,
+    cursor: Cursor,
 }
 
-impl Btree {
-    /// create a new Btree
-    pub fn new() -> Btree {
-        Btree { root: None }
+impl Iterator for Range<'_> {
+    type Item = io::Result<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.step(self.tree)
     }
+}
 
-    /// Inserts a key-value pair into the BTree
-    ///
-    /// Parameters:
-    ///
-    /// > key: String
-    /// > value: String
-    pub fn insert(&mut self, key: String, value: String) {
-        // if there is an existing root
-        if let Some(ref mut root) = self.root {
-            // if self.root is Some(none) then return a mutable
-            // reference to that node
-
-            if root.keys.len() == (MIN_DEGREE * 2 - 1) {
-                // if the root is full -- we gotta increase the height
-                let mut new_root = Box::new(Node::new(false));
-                // clone the current root and make it as the first child of the new root .. but why
-                // so?
-                new_root.children.push(Some(root.clone()));
-                new_root.split_child(0);
-                new_root.insert_not_full(key, value);
-                self.root = Some(new_root);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    fn test_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rocs-btree-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// xorshift, the tests only need something that looks random and can be replayed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// mostly small values, now and then one that needs overflow pages
+    fn random_value(rng: &mut Rng) -> Vec<u8> {
+        let len = match rng.below(10) {
+            0 => 600 + rng.below(10_000),
+            _ => rng.below(100),
+        };
+        let byte = rng.below(256) as u8;
+        vec![byte; len as usize]
+    }
+
+    fn random_change(rng: &mut Rng, tree: &mut BPlusTree, model: &mut BTreeMap<String, Vec<u8>>) {
+        let key = format!("key{:05}", rng.below(3000));
+
+        if rng.below(3) == 0 {
+            assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+        } else {
+            let value = random_value(rng);
+            assert_eq!(
+                tree.insert(key.clone(), value.clone()).unwrap(),
+                model.insert(key, value)
+            );
+        }
+    }
+
+    fn assert_same(tree: &mut BPlusTree, model: &BTreeMap<String, Vec<u8>>) {
+        let entries = tree
+            .range(Bound::Unbounded, Bound::Unbounded, false)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let expected: Vec<_> = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        assert_eq!(entries, expected);
+
+        let mut entries = tree
+            .range(Bound::Unbounded, Bound::Unbounded, true)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        entries.reverse();
+        assert_eq!(entries, expected);
+        assert_eq!(tree.len, model.len() as u64);
+    }
+
+    #[test]
+    fn matches_a_btreemap_across_reopens() {
+        let path = test_file("model");
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut model = BTreeMap::new();
+        let mut tree = BPlusTree::open(&path).unwrap();
+
+        for round in 0..20 {
+            for _ in 0..500 {
+                random_change(&mut rng, &mut tree, &mut model);
+            }
+
+            let start = format!("key{:05}", rng.below(3000));
+            let end = format!("key{:05}", rng.below(3000));
+            let found = tree
+                .range(Bound::Included(&start), Bound::Excluded(&end), false)
+                .unwrap()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+            let expected: Vec<_> = if start < end {
+                model
+                    .range(start.clone()..end.clone())
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
             } else {
-                root.insert_not_full(key, value);
+                Vec::new()
+            };
+            assert_eq!(
+                found, expected,
+                "range {}..{} in round {}",
+                start, end, round
+            );
+
+            // backwards, stopping early like a LIMIT does
+            let limit = rng.below(50) as usize;
+            let found = tree
+                .range(Bound::Excluded(&start), Bound::Included(&end), true)
+                .unwrap()
+                .take(limit)
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+            let expected: Vec<_> = if start < end {
+                model
+                    .range((Bound::Excluded(start.clone()), Bound::Included(end.clone())))
+                    .rev()
+                    .take(limit)
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            assert_eq!(found, expected, "reverse range {}..={}", start, end);
+
+            if round % 3 == 0 {
+                drop(tree);
+                tree = BPlusTree::open(&path).unwrap();
             }
-        } else {
-            // simply insert right? .. explain here too!
-            let mut root = Box::new(Node::new(true));
-            root.keys.push(key);
-            root.values.push(value);
-            self.root = Some(root);
+            assert_same(&mut tree, &model);
         }
+
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_crash_leaves_the_last_commit() {
+        let path = test_file("crash");
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut model = BTreeMap::new();
+
+        for _ in 0..5 {
+            let mut tree = BPlusTree::open(&path).unwrap();
+            assert_same(&mut tree, &model);
+            // a small pool, so that the pages changed after the commit get written out too
+            tree.pager.capacity = 8;
+
+            for _ in 0..1000 {
+                random_change(&mut rng, &mut tree, &mut model);
+            }
+            tree.flush().unwrap();
+            let committed = model.clone();
+
+            for _ in 0..1000 {
+                random_change(&mut rng, &mut tree, &mut model);
+            }
+            // the crash -- no flush on the way out
+            std::mem::forget(tree);
+            model = committed;
+        }
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_same(&mut tree, &model);
+
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_torn_meta_page_falls_back_to_the_other_one() {
+        let path = test_file("torn");
+        let mut tree = BPlusTree::open(&path).unwrap();
+        tree.insert("a".to_string(), b"1".to_vec()).unwrap();
+        tree.set_lsn(1);
+        tree.flush().unwrap();
+        tree.insert("b".to_string(), b"2".to_vec()).unwrap();
+        tree.set_lsn(2);
+        tree.flush().unwrap();
+
+        // break the meta page of the last commit, as if the crash hit while writing it
+        let slot = tree.seq % META_SLOTS;
+        std::mem::forget(tree);
+        assert_eq!(peek_lsn(&path).unwrap(), Some(2));
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(slot * PAGE_SIZE as u64 + 30))
+            .unwrap();
+        file.write_all(b"garbage").unwrap();
+        drop(file);
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_eq!(tree.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tree.get("b").unwrap(), None);
+        assert_eq!(tree.committed_lsn(), 1);
+        assert_eq!(peek_lsn(&path).unwrap(), Some(1));
+
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn read_view(tree: &mut BPlusTree, view: &View) -> Vec<(String, Vec<u8>)> {
+        let mut cursor = tree.view_cursor(view).unwrap();
+        std::iter::from_fn(|| cursor.step(tree))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap()
     }
-}
 
-// Okay .. so I understood and implemented this much .. but the deletion part is a huge pain in the
-// ass .. so I'll complete my own implementation sometime else ...
+    #[test]
+    fn a_view_stays_as_it_was() {
+        let path = test_file("view");
+        let mut rng = Rng(0x1405_7b7e_f767_814f);
+        let mut model = BTreeMap::new();
+        let mut tree = BPlusTree::open(&path).unwrap();
+        tree.pager.capacity = 8;
+
+        for _ in 0..1000 {
+            random_change(&mut rng, &mut tree, &mut model);
+        }
+        let mut views = Vec::new();
+        for round in 0..6 {
+            views.push((tree.view(), model.clone()));
+            for _ in 0..500 {
+                random_change(&mut rng, &mut tree, &mut model);
+            }
+            if round % 2 == 0 {
+                tree.flush().unwrap();
+            }
+
+            for (view, frozen) in &views {
+                let expected: Vec<_> = frozen.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                assert_eq!(read_view(&mut tree, view), expected, "round {}", round);
+            }
+            // let go of them out of order
+            if round == 3 {
+                tree.release_view(views.remove(1).0);
+            }
+        }
+        for (view, _) in views {
+            tree.release_view(view);
+        }
+        assert!(tree.pager.held.is_empty());
+
+        // the pages the views kept are handed out again without breaking anything
+        for _ in 0..2000 {
+            random_change(&mut rng, &mut tree, &mut model);
+        }
+        assert_same(&mut tree, &model);
+        drop(tree);
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_same(&mut tree, &model);
+
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changes_during_a_commit_wait_for_the_next_one() {
+        let path = test_file("commit");
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        let mut model = BTreeMap::new();
+        let mut tree = BPlusTree::open(&path).unwrap();
+        tree.pager.capacity = 8;
+
+        for round in 0..4 {
+            for _ in 0..1000 {
+                random_change(&mut rng, &mut tree, &mut model);
+            }
+
+            let commit = tree.begin_commit().unwrap();
+            let committed = model.clone();
+            // the tree goes on while the commit is written
+            for _ in 0..500 {
+                random_change(&mut rng, &mut tree, &mut model);
+            }
+            let written = commit.write();
+            tree.end_commit(commit, written).unwrap();
+            assert_same(&mut tree, &model);
+
+            if round == 3 {
+                for _ in 0..500 {
+                    random_change(&mut rng, &mut tree, &mut model);
+                }
+                // the crash, only the commit is left
+                std::mem::forget(tree);
+                model = committed;
+                break;
+            }
+        }
+
+        let mut tree = BPlusTree::open(&path).unwrap();
+        assert_same(&mut tree, &model);
+
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
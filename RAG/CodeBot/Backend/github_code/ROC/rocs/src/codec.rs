@@ -0,0 +1,192 @@
+// ROC/rocs/src/codec.rs
+
+use crate::config::{Compression, CONFIG};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use once_cell::sync::Lazy;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Compression and encryption at rest, for snapshot bodies and WAL record payloads.
+//
+// Encoding compresses first (ciphertext does not compress), then seals the result with
+// ChaCha20-Poly1305 under a fresh random nonce:
+//
+//  nonce (12 bytes) | ciphertext | tag (16 bytes)
+//
+// The caller passes associated data tying the bytes to their place -- the LSN of the record or
+// snapshot -- so sealed bytes moved somewhere else fail to open just like tampered ones.
+
+const NONCE_LEN: usize = 12;
+
+/// the cipher for ROC_KEY_FILE, read the first time it is needed
+static CIPHER: Lazy<Result<Option<ChaCha20Poly1305>, String>> = Lazy::new(|| {
+    CONFIG
+        .key_file
+        .as_ref()
+        .map(|path| load_key(path).map_err(|msg| format!("key file {:?}: {}", path, msg)))
+        .transpose()
+});
+
+/// Reads a 256 bit key, either 32 raw bytes or 64 hex digits
+fn load_key(path: &Path) -> Result<ChaCha20Poly1305, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+
+    let key = match std::str::from_utf8(&data).map(str::trim) {
+        Ok(hex) if hex.len() == 64 => (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| "not a hex key".to_string())?,
+        _ if data.len() == 32 => data,
+        _ => return Err("expected 32 bytes or 64 hex digits".to_string()),
+    };
+
+    ChaCha20Poly1305::new_from_slice(&key).map_err(|e| e.to_string())
+}
+
+/// The cipher for ROC_KEY_FILE, None if there is no key file
+pub(crate) fn cipher() -> io::Result<Option<&'static ChaCha20Poly1305>> {
+    CIPHER
+        .as_ref()
+        .map(Option::as_ref)
+        .map_err(|msg| io::Error::other(msg.clone()))
+}
+
+/// The cipher to read encrypted data with, an error if there is no key file
+pub(crate) fn required_cipher() -> io::Result<&'static ChaCha20Poly1305> {
+    cipher()?.ok_or_else(|| io::Error::other("the data is encrypted, ROC_KEY_FILE is needed"))
+}
+
+/// How a compression is stored in the WAL segment header
+pub(crate) fn compression_id(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => 0,
+        Compression::Lz4 => 1,
+        Compression::Zstd => 2,
+    }
+}
+
+pub(crate) fn compression_from_id(id: u8) -> Option<Compression> {
+    match id {
+        0 => Some(Compression::None),
+        1 => Some(Compression::Lz4),
+        2 => Some(Compression::Zstd),
+        _ => None,
+    }
+}
+
+/// Compresses data, then seals it if a cipher is given
+pub(crate) fn encode(
+    data: &[u8],
+    compression: Compression,
+    cipher: Option<&ChaCha20Poly1305>,
+    aad: &[u8],
+) -> io::Result<Vec<u8>> {
+    let compressed = match compression {
+        Compression::None => data.to_vec(),
+        Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+        Compression::Zstd => zstd::encode_all(data, 0)?,
+    };
+
+    let Some(cipher) = cipher else {
+        return Ok(compressed);
+    };
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &compressed,
+                aad,
+            },
+        )
+        .map_err(|_| io::Error::other("encryption failed"))?;
+
+    let mut out = Vec::with_capacity(NONCE_LEN + sealed.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+/// Undoes `encode`, a wrong key or tampered bytes are an InvalidData error
+pub(crate) fn decode(
+    data: &[u8],
+    compression: Compression,
+    cipher: Option<&ChaCha20Poly1305>,
+    aad: &[u8],
+) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let opened;
+    let compressed = match cipher {
+        None => data,
+        Some(cipher) => {
+            if data.len() < NONCE_LEN {
+                return Err(invalid("encrypted data too short"));
+            }
+            let (nonce, sealed) = data.split_at(NONCE_LEN);
+            opened = cipher
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+                .map_err(|_| invalid("can not decrypt, wrong key or tampered data"))?;
+            &opened[..]
+        }
+    };
+
+    match compression {
+        Compression::None => Ok(compressed.to_vec()),
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(compressed)
+            .map_err(|e| invalid(&format!("bad lz4 data: {}", e))),
+        Compression::Zstd => {
+            zstd::decode_all(compressed).map_err(|e| invalid(&format!("bad zstd data: {}", e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new_from_slice(&[byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn round_trips_with_every_compression_and_key() {
+        let data = b"the same value over and over, the same value over and over".repeat(50);
+        let cipher = key(7);
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            for cipher in [None, Some(&cipher)] {
+                let encoded = encode(&data, compression, cipher, b"lsn 1").unwrap();
+                if compression != Compression::None {
+                    assert!(encoded.len() < data.len());
+                }
+                let decoded = decode(&encoded, compression, cipher, b"lsn 1").unwrap();
+                assert_eq!(decoded, data);
+            }
+        }
+    }
+
+    #[test]
+    fn a_wrong_key_or_tampered_bytes_are_refused() {
+        let data = b"secret".to_vec();
+        let encoded = encode(&data, Compression::Lz4, Some(&key(1)), b"lsn 1").unwrap();
+        assert!(!encoded.windows(data.len()).any(|w| w == &data[..]));
+
+        let refused = |data: &[u8], cipher: &ChaCha20Poly1305, aad: &[u8]| {
+            let err = decode(data, Compression::Lz4, Some(cipher), aad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        };
+        refused(&encoded, &key(2), b"lsn 1");
+        // sealed for somewhere else
+        refused(&encoded, &key(1), b"lsn 2");
+        for at in [0, NONCE_LEN, encoded.len() - 1] {
+            let mut tampered = encoded.clone();
+            tampered[at] ^= 1;
+            refused(&tampered, &key(1), b"lsn 1");
+        }
+        refused(&encoded[..NONCE_LEN - 1], &key(1), b"lsn 1");
+    }
+}
@@ -1,34 +1,145 @@
+use crate::value::Value;
 use serde::{self, Deserialize, Serialize};
 
+/// A request and, filled in, its reply
+///
+/// Writes go into the WAL with bincode, which stores the position of the variant -- new ones go
+/// at the end so that the old records still read back.
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Command {
     Ping,
     Store {
         key: String,
-        value: usize,
+        value: Value,
+        /// unix time in milliseconds at which the key expires
+        #[serde(default)]
+        expires_at: Option<u64>,
     },
     Fetch {
         key: String,
-        value: Option<usize>,
+        value: Option<Value>,
     },
     Update {
         key: String,
-        value: usize,
+        value: Value,
     },
     Delete {
         key: String,
     },
     Range {
-        start: usize,
-        end: usize,
-        result: Vec<(String, usize)>,
+        start: Value,
+        end: Value,
+        result: Vec<(String, Value)>,
     },
     List {
-        entries: Vec<(String, usize)>,
+        entries: Vec<(String, Value)>,
+    },
+    Scan {
+        start: String,
+        end: String,
+        result: Vec<(String, Value)>,
+    },
+    Prefix {
+        prefix: String,
+        result: Vec<(String, Value)>,
+    },
+    Expire {
+        key: String,
+        /// unix time in milliseconds at which the key expires
+        expires_at: u64,
+    },
+    Ttl {
+        key: String,
+        /// remaining seconds, None if the key never expires
+        ttl: Option<u64>,
+    },
+    Persist {
+        key: String,
     },
     Shutdown,
     Crash,
     ERR {
         msg: String,
     },
+    /// starts queueing writes on the connection
+    Multi,
+    /// a write was queued, it runs with EXEC
+    Queued,
+    /// the queued writes, applied all together or not at all
+    Exec {
+        commands: Vec<Command>,
+    },
+    /// the queued writes were dropped
+    Discard,
+    /// sets the value only if the key still holds the expected one
+    Cas {
+        key: String,
+        expected: Value,
+        value: Value,
+    },
+    /// EXEC fails if any of these keys changes before it
+    Watch {
+        keys: Vec<String>,
+    },
+    Unwatch,
+    /// INCR, DECR, INCRBY and DECRBY -- a missing key counts as 0
+    Incr {
+        key: String,
+        by: i64,
+        /// the value after the increment, filled in when the command runs
+        ///
+        /// the WAL record keeps it, so a replay sets the key to it instead of adding again
+        value: Option<i64>,
+        /// the expiry the key ends up with, filled in along with value
+        ///
+        /// logged for the same reason -- an INCR of an expired key starts over without one, but
+        /// a replay can not tell that the old entry had expired
+        expires_at: Option<u64>,
+    },
+    /// STORE with NX or XX
+    StoreIf {
+        key: String,
+        value: Value,
+        /// unix time in milliseconds at which the key expires
+        expires_at: Option<u64>,
+        condition: Condition,
+        /// whether the condition held and the value was stored, filled in when the command runs
+        stored: Option<bool>,
+    },
+    /// stores a value and replies with the one before
+    GetSet {
+        key: String,
+        value: Value,
+        /// filled in when the command runs, None if the key was not there
+        old: Option<Value>,
+    },
+    /// the values of many keys, None for the ones that are not there
+    MGet {
+        entries: Vec<(String, Option<Value>)>,
+    },
+    /// stores many keys at once, as one WAL record
+    MSet {
+        entries: Vec<(String, Value)>,
+    },
+    /// deletes many keys at once, as one WAL record
+    MDel {
+        keys: Vec<String>,
+        /// how many of the keys were there, filled in when the command runs
+        deleted: Option<u64>,
+    },
+    /// takes a snapshot right away
+    Snapshot {
+        /// every write up to this LSN is in the snapshot
+        lsn: u64,
+    },
+}
+
+/// When a STORE goes through
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// only if the key is not there
+    NX,
+    /// only if the key is there
+    XX,
 }
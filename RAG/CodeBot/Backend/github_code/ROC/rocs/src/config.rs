@@ -0,0 +1,189 @@
+// ROC/rocs/src/config.rs
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::PathBuf;
+
+/// Startup configuration of the server, read from the environment
+///
+/// ROC_ADDR      -- address the server listens on for clients (default: "127.0.0.1:9879")
+/// ROC_ENGINE    -- storage engine: "memory" (default), "btree" or "lsm"
+/// ROC_DATA_DIR  -- where persistent engines keep their files (default: "data")
+/// ROC_WAL_SEGMENT_SIZE -- bytes after which the WAL moves on to a new segment (default: 16 MiB)
+/// ROC_SNAPSHOT_KEEP -- how many snapshots are kept around to fall back on (default: 3)
+/// ROC_FSYNC     -- when the WAL is synced to disk: "always" (before every reply), "everysec"
+///                  (default, up to a second of writes can be lost) or "never" (left to the OS)
+/// ROC_SNAPSHOT_FORMAT -- how new snapshots are written: "json" (default) or "bincode", which is
+///                  smaller and faster to load. Snapshots of either format are always readable
+/// ROC_COMPRESSION -- compresses snapshots and the WAL records written from now on: "none"
+///                  (default), "lz4" or "zstd"
+/// ROC_KEY_FILE  -- encrypts them as well (ChaCha20-Poly1305) with the 256 bit key in this file,
+///                  32 raw bytes or 64 hex digits. What was written with a key needs it to be read.
+///                  Only with the memory engine, the files of the others are not encrypted
+///
+/// A snapshot is taken on its own by whichever of these comes first, 0 turns one off:
+///
+/// ROC_SNAPSHOT_INTERVAL  -- seconds since the last snapshot (default: 30)
+/// ROC_SNAPSHOT_WRITES    -- writes since the last snapshot (default: off)
+/// ROC_SNAPSHOT_WAL_BYTES -- bytes logged to the WAL since the last snapshot (default: off)
+pub(crate) static CONFIG: Lazy<Config> = Lazy::new(|| match Config::from_env() {
+    Ok(config) => config,
+    Err(msg) => {
+        eprintln!("Invalid configuration: {}", msg);
+        std::process::exit(1);
+    }
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EngineKind {
+    Memory,
+    BTree,
+    Lsm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FsyncPolicy {
+    Always,
+    EverySec,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SnapshotFormat {
+    #[default]
+    Json,
+    Bincode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// When snapshots are taken on their own, see CONFIG -- None is a trigger that is off
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SnapshotPolicy {
+    pub(crate) interval_secs: Option<u64>,
+    pub(crate) writes: Option<u64>,
+    pub(crate) wal_bytes: Option<u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Config {
+    pub(crate) addr: String,
+    pub(crate) engine: EngineKind,
+    pub(crate) data_dir: PathBuf,
+    pub(crate) wal_segment_size: u64,
+    pub(crate) snapshot_keep: usize,
+    pub(crate) fsync: FsyncPolicy,
+    pub(crate) snapshot_format: SnapshotFormat,
+    pub(crate) snapshot_policy: SnapshotPolicy,
+    pub(crate) compression: Compression,
+    pub(crate) key_file: Option<PathBuf>,
+}
+
+impl Config {
+    fn from_env() -> Result<Config, String> {
+        let addr = env::var("ROC_ADDR").unwrap_or_else(|_| "127.0.0.1:9879".to_string());
+
+        let engine = match env::var("ROC_ENGINE") {
+            Err(_) => EngineKind::Memory,
+            Ok(name) => match name.to_lowercase().as_str() {
+                "memory" => EngineKind::Memory,
+                "btree" => EngineKind::BTree,
+                "lsm" => EngineKind::Lsm,
+                other => return Err(format!("unknown storage engine {:?}", other)),
+            },
+        };
+
+        let data_dir = env::var("ROC_DATA_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("data"));
+
+        let wal_segment_size = match env::var("ROC_WAL_SEGMENT_SIZE") {
+            Err(_) => 16 * 1024 * 1024,
+            Ok(size) => size
+                .parse::<u64>()
+                .map_err(|_| format!("invalid WAL segment size {:?}", size))?,
+        };
+
+        let snapshot_keep = match env::var("ROC_SNAPSHOT_KEEP") {
+            Err(_) => 3,
+            Ok(keep) => match keep.parse::<usize>() {
+                Ok(keep) if keep > 0 => keep,
+                _ => return Err(format!("invalid number of snapshots to keep {:?}", keep)),
+            },
+        };
+
+        let fsync = match env::var("ROC_FSYNC") {
+            Err(_) => FsyncPolicy::EverySec,
+            Ok(policy) => match policy.to_lowercase().as_str() {
+                "always" => FsyncPolicy::Always,
+                "everysec" => FsyncPolicy::EverySec,
+                "never" => FsyncPolicy::Never,
+                other => return Err(format!("unknown fsync policy {:?}", other)),
+            },
+        };
+
+        let snapshot_format = match env::var("ROC_SNAPSHOT_FORMAT") {
+            Err(_) => SnapshotFormat::Json,
+            Ok(format) => match format.to_lowercase().as_str() {
+                "json" => SnapshotFormat::Json,
+                "bincode" => SnapshotFormat::Bincode,
+                other => return Err(format!("unknown snapshot format {:?}", other)),
+            },
+        };
+
+        let snapshot_policy = SnapshotPolicy {
+            interval_secs: trigger("ROC_SNAPSHOT_INTERVAL", 30)?,
+            writes: trigger("ROC_SNAPSHOT_WRITES", 0)?,
+            wal_bytes: trigger("ROC_SNAPSHOT_WAL_BYTES", 0)?,
+        };
+
+        let compression = match env::var("ROC_COMPRESSION") {
+            Err(_) => Compression::None,
+            Ok(compression) => match compression.to_lowercase().as_str() {
+                "none" => Compression::None,
+                "lz4" => Compression::Lz4,
+                "zstd" => Compression::Zstd,
+                other => return Err(format!("unknown compression {:?}", other)),
+            },
+        };
+
+        let key_file = env::var("ROC_KEY_FILE").ok().map(PathBuf::from);
+        if key_file.is_some() && engine != EngineKind::Memory {
+            // their pages and tables would sit on disk in plaintext next to the encrypted WAL
+            return Err("ROC_KEY_FILE only works with the memory engine".to_string());
+        }
+
+        Ok(Config {
+            addr,
+            engine,
+            data_dir,
+            wal_segment_size,
+            snapshot_keep,
+            fsync,
+            snapshot_format,
+            snapshot_policy,
+            compression,
+            key_file,
+        })
+    }
+}
+
+/// Reads a snapshot trigger, 0 means off
+fn trigger(name: &str, default: u64) -> Result<Option<u64>, String> {
+    let value = match env::var(name) {
+        Err(_) => default,
+        Ok(value) => value
+            .parse::<u64>()
+            .map_err(|_| format!("invalid {} {:?}", name, value))?,
+    };
+    Ok(Some(value).filter(|n| *n > 0))
+}
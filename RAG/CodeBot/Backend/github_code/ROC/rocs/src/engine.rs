@@ -0,0 +1,355 @@
+// ROC/rocs/src/engine.rs
+
+use crate::btree::{self, BPlusTree, Cursor, View};
+use crate::config::{Config, EngineKind};
+use crate::lsm::{self, LsmEngine};
+use crate::store::Entry;
+
+use imbl::OrdMap;
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+/// A point in time copy of the entries of an engine, in key order
+///
+/// It stays valid while the engine keeps changing.
+pub(crate) type Snapshot = Box<dyn Iterator<Item = io::Result<(String, Entry)>> + Send>;
+
+/// What is left of a `StorageEngine::checkpoint` once the store lock is let go of
+pub(crate) type Checkpoint = Box<dyn FnOnce() -> io::Result<()> + Send>;
+
+/// Where the entries of the store are kept
+///
+/// `store` holds the engine behind its RwLock, so reads get `&self` and writes `&mut self`.
+/// Expiry and the value index are handled by `store` on top of this.
+pub(crate) trait StorageEngine: Send + Sync {
+    fn get(&self, key: &str) -> io::Result<Option<Entry>>;
+
+    /// stores the entry
+    ///
+    /// Nothing comes back about the entry it replaces, the store reads that itself when it
+    /// needs it -- writing must not have to read, an LSM engine would look through every level.
+    fn put(&mut self, key: String, entry: Entry) -> io::Result<()>;
+
+    /// Refuses a key the engine can not hold
+    ///
+    /// Writes are checked with this before they go to the WAL -- once logged, a write has to
+    /// make it into the engine, so every limit the engine has must show up here.
+    fn check_key(&self, _key: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// removes the key, if it is there
+    fn delete(&mut self, key: &str) -> io::Result<()>;
+
+    /// Calls visit for every entry with a key within the bounds, in key order (descending if
+    /// rev is set), until visit returns false
+    fn range(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        rev: bool,
+        visit: &mut dyn FnMut(&str, &Entry) -> bool,
+    ) -> io::Result<()>;
+
+    /// Calls visit for every entry in key order until it returns false
+    fn iter(&self, visit: &mut dyn FnMut(&str, &Entry) -> bool) -> io::Result<()> {
+        self.range(Bound::Unbounded, Bound::Unbounded, false, visit)
+    }
+
+    /// Takes a snapshot of the engine
+    ///
+    /// This runs under the store lock, which keeps every write waiting -- take the point in time
+    /// view here and leave the copying to the iterator, which runs without the lock.
+    fn snapshot(&self) -> io::Result<Snapshot>;
+
+    /// Throws away everything in the engine and replaces it with entries
+    ///
+    /// The engine holds no LSN afterwards, until the next `set_lsn` and `sync`.
+    fn restore(&mut self, entries: &mut dyn Iterator<Item = (String, Entry)>) -> io::Result<()>;
+
+    /// Tells the engine it holds the WAL up to lsn
+    ///
+    /// Only called once a whole WAL record is applied -- a record that reached the engine halfway
+    /// could not be replayed on top of it.
+    fn set_lsn(&mut self, _lsn: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The LSN of the WAL the engine holds on disk, a restart carries on from the record after it
+    ///
+    /// 0 if there is nothing to carry on from, None for engines that keep nothing on disk.
+    fn persisted_lsn(&self) -> Option<u64> {
+        None
+    }
+
+    /// Starts getting everything up to the last `set_lsn` on disk, nothing to do for in-memory
+    /// engines
+    ///
+    /// Runs under the store lock like `snapshot` -- only take down what has to be written here,
+    /// the writing and syncing goes into the returned job. Nothing is on disk before it ran.
+    ///
+    /// An engine may leave the last few writes to the WAL, `persisted_lsn` says how far it got.
+    fn checkpoint(&self) -> io::Result<Checkpoint> {
+        Ok(Box::new(|| Ok(())))
+    }
+
+    /// Makes sure everything up to the last `set_lsn` is on disk, the WAL up to it is not needed
+    /// anymore afterwards
+    fn sync(&self) -> io::Result<()> {
+        self.checkpoint()?()
+    }
+}
+
+/// Opens the engine selected in the config
+pub(crate) fn open(config: &Config) -> io::Result<Box<dyn StorageEngine>> {
+    match config.engine {
+        EngineKind::Memory => {
+            eprintln!("Using the in-memory storage engine");
+            Ok(Box::new(MemoryEngine::default()))
+        }
+        EngineKind::BTree => {
+            fs::create_dir_all(&config.data_dir)?;
+            let path = config.data_dir.join("store.btree");
+            eprintln!("Using the B+tree storage engine at {:?}", path);
+            Ok(Box::new(BTreeEngine::open(path)?))
+        }
+        EngineKind::Lsm => {
+            let dir = config.data_dir.join("lsm");
+            eprintln!("Using the LSM storage engine at {:?}", dir);
+            Ok(Box::new(LsmEngine::open(dir)?))
+        }
+    }
+}
+
+/// The LSN of the WAL the engine selected in the config holds on disk, like
+/// `StorageEngine::persisted_lsn` but without opening the engine -- for looking at the files of a
+/// server that is not running
+///
+/// None for the memory engine and for a disk engine that has no files yet.
+pub(crate) fn peek_lsn(config: &Config) -> io::Result<Option<u64>> {
+    match config.engine {
+        EngineKind::Memory => Ok(None),
+        EngineKind::BTree => btree::peek_lsn(&config.data_dir.join("store.btree")),
+        EngineKind::Lsm => lsm::peek_lsn(&config.data_dir.join("lsm")),
+    }
+}
+
+/// BTreeMap::range panics on these -- for us they are just empty ranges
+fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
+/// Keeps everything in memory, in a sorted map like ROC always did
+///
+/// The map is a persistent one: a clone shares everything with the original and only copies
+/// what is changed afterwards, which makes snapshots free.
+#[derive(Default)]
+pub(crate) struct MemoryEngine {
+    entries: OrdMap<String, Entry>,
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &str) -> io::Result<Option<Entry>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn put(&mut self, key: String, entry: Entry) -> io::Result<()> {
+        self.entries.insert(key, entry);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.entries.remove(key);
+        Ok(())
+    }
+
+    fn range(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        rev: bool,
+        visit: &mut dyn FnMut(&str, &Entry) -> bool,
+    ) -> io::Result<()> {
+        if is_empty_range(start, end) {
+            return Ok(());
+        }
+
+        let range = self.entries.range::<_, str>((start, end));
+        if rev {
+            for (key, entry) in range.rev() {
+                if !visit(key, entry) {
+                    break;
+                }
+            }
+        } else {
+            for (key, entry) in range {
+                if !visit(key, entry) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<Snapshot> {
+        Ok(Box::new(self.entries.clone().into_iter().map(Ok)))
+    }
+
+    fn restore(&mut self, entries: &mut dyn Iterator<Item = (String, Entry)>) -> io::Result<()> {
+        self.entries = entries.collect();
+        Ok(())
+    }
+}
+
+/// Keeps the entries in the disk backed B+tree from `btree`, encoded with bincode
+pub(crate) struct BTreeEngine {
+    // the tree needs &mut even to read since its buffer pool changes underneath, and snapshots
+    // and checkpoints hold on to it after the store lock is gone
+    tree: Arc<Mutex<BPlusTree>>,
+}
+
+impl BTreeEngine {
+    pub(crate) fn open<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        Ok(BTreeEngine {
+            tree: Arc::new(Mutex::new(BPlusTree::open(path)?)),
+        })
+    }
+}
+
+/// The entries of a view of the tree, decoded one by one as they are asked for
+struct TreeSnapshot {
+    tree: Arc<Mutex<BPlusTree>>,
+    view: Option<View>,
+    cursor: Cursor,
+}
+
+impl Iterator for TreeSnapshot {
+    type Item = io::Result<(String, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut tree = self.tree.lock().unwrap();
+
+        let item = self.cursor.step(&mut tree)?;
+        Some(item.and_then(|(key, bytes)| Ok((key, decode(&bytes)?))))
+    }
+}
+
+impl Drop for TreeSnapshot {
+    fn drop(&mut self) {
+        if let (Some(view), Ok(mut tree)) = (self.view.take(), self.tree.lock()) {
+            tree.release_view(view);
+        }
+    }
+}
+
+fn encode(entry: &Entry) -> io::Result<Vec<u8>> {
+    bincode::serialize(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Entry> {
+    bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl StorageEngine for BTreeEngine {
+    fn get(&self, key: &str) -> io::Result<Option<Entry>> {
+        let mut tree = self.tree.lock().unwrap();
+
+        tree.get(key)?.map(|bytes| decode(&bytes)).transpose()
+    }
+
+    fn put(&mut self, key: String, entry: Entry) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+
+        tree.insert(key, encode(&entry)?)?;
+        Ok(())
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), String> {
+        btree::check_key(key)
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+
+        tree.delete(key)?;
+        Ok(())
+    }
+
+    fn range(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        rev: bool,
+        visit: &mut dyn FnMut(&str, &Entry) -> bool,
+    ) -> io::Result<()> {
+        if is_empty_range(start, end) {
+            return Ok(());
+        }
+
+        let mut tree = self.tree.lock().unwrap();
+        for item in tree.range(start, end, rev)? {
+            let (key, bytes) = item?;
+            if !visit(&key, &decode(&bytes)?) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<Snapshot> {
+        let mut tree = self.tree.lock().unwrap();
+
+        // the tree never changes a page a view uses, so there is nothing to copy
+        let view = tree.view();
+        let cursor = match tree.view_cursor(&view) {
+            Ok(cursor) => cursor,
+            Err(e) => {
+                tree.release_view(view);
+                return Err(e);
+            }
+        };
+
+        Ok(Box::new(TreeSnapshot {
+            tree: Arc::clone(&self.tree),
+            view: Some(view),
+            cursor,
+        }))
+    }
+
+    fn restore(&mut self, entries: &mut dyn Iterator<Item = (String, Entry)>) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+
+        tree.clear()?;
+        for (key, entry) in entries {
+            tree.insert(key, encode(&entry)?)?;
+        }
+        tree.flush()
+    }
+
+    fn set_lsn(&mut self, lsn: u64) -> io::Result<()> {
+        self.tree.lock().unwrap().set_lsn(lsn);
+        Ok(())
+    }
+
+    fn persisted_lsn(&self) -> Option<u64> {
+        Some(self.tree.lock().unwrap().committed_lsn())
+    }
+
+    fn checkpoint(&self) -> io::Result<Checkpoint> {
+        let commit = self.tree.lock().unwrap().begin_commit()?;
+
+        let tree = Arc::clone(&self.tree);
+        Ok(Box::new(move || {
+            let written = commit.write();
+            tree.lock().unwrap().end_commit(commit, written)
+        }))
+    }
+}
@@ -0,0 +1,19 @@
+use crate::store;
+use std::thread;
+use std::time::Duration;
+
+/// Starts a background thread that drops expired keys every `interval_secs`
+///
+/// Reads already ignore expired keys, this just makes sure the memory of keys that are never
+/// read again is given back.
+pub fn sweep_expired(interval_secs: u64) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval_secs));
+
+        match store::purge_expired() {
+            Ok(0) => {}
+            Ok(removed) => eprintln!("Expiry sweep removed {} keys", removed),
+            Err(e) => eprintln!("Expiry sweep failed: {}", e),
+        }
+    });
+}
@@ -0,0 +1,20 @@
+// ROC/rocs/src/lib.rs
+
+// Everything lives in the library so that the rocs server and roc-tool (src/bin) share the
+// storage, WAL and snapshot code.
+
+mod btree;
+mod codec;
+mod command;
+mod config;
+mod engine;
+mod expiry;
+mod logger;
+mod lsm;
+mod recovery;
+pub mod server;
+mod snapshot;
+mod store;
+pub mod tool;
+mod value;
+mod writer;
@@ -1,154 +1,987 @@
 // Code/ROC/rocs/src/logger.rs
 
+use crate::codec;
 use crate::command::Command;
-use serde_json;
+use crate::config::{Compression, FsyncPolicy, CONFIG};
+use crate::store;
+use crate::value;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
-// use std::time::{SystemTime, UNIX_EPOCH}; // “1970-01-01 00:00:00 UTC”
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
-/// Write Ahead Logging [WAL]     
-/// append a command entry of type &Command
-pub(crate) fn store_log(com: &Command) {
-    let log_dir = Path::new("logs");
+// The WAL is a series of segment files logs/wal-<first lsn>.log. New records go to the newest
+// segment, once that grows past the configured size the next batch starts a new one. Segments
+// are only deleted once a snapshot covers every record in them.
+//
+// How soon records reach the disk is up to CONFIG.fsync: after every record, once a second from
+// a background thread, or whenever the OS gets to it.
+//
+// Records come in batches from the writer thread (see writer.rs), each batch goes out with a
+// single write and -- with fsync=always -- a single sync, so under load many writes share one
+// fsync instead of each waiting for its own.
+//
+// Segment layout: magic "ROCWAL" | version u16 | compression u8 | encrypted u8 | records...
+//
+// (versions 1 and 2 stop after the version). The payloads of a segment are compressed and
+// encrypted as its header says, see codec.rs -- the LSN of the record is the associated data.
+// A segment only ever holds one kind, so after a restart with other settings the next record
+// starts a new segment.
+//
+// WAL record layout, all integers little endian:
+//
+//  len u32 | crc32 u32 | lsn u64 | timestamp u64 | type u8 | payload
+//
+// len counts the bytes after the crc (lsn, timestamp, type and payload), the crc covers the same
+// bytes. Every record gets the next LSN, so they only ever go up within the log. The timestamp is
+// the unix time in milliseconds the record was written at, version 1 segments do not have it.
+//
+// Before segments, ROC logged every command as a line of JSON to logs/wal.log. read_wal turns
+// such a file into the first segment once, see convert_legacy_wal.
+pub(crate) const WAL_DIR: &str = "logs";
+const SEGMENT_MAGIC: &[u8; 6] = b"ROCWAL";
+const SEGMENT_VERSION: u16 = 3;
+// magic and version, all there is to the header of versions 1 and 2
+const SEGMENT_PREFIX: usize = 8;
+const SEGMENT_HEADER: usize = 10;
 
-    if !log_dir.exists() {
-        fs::create_dir(log_dir).expect("Unable to create the log directory");
+const RECORD_HEADER: usize = 8;
+// the smallest body of any version, a version 1 record without a payload
+const MIN_BODY: usize = 9;
+// nothing we log comes close, a bigger len means the header itself is garbage
+pub(crate) const MAX_BODY: usize = 64 * 1024 * 1024;
+// the biggest command that gets logged -- compression and encryption can add a little, the
+// record still has to fit in MAX_BODY after them
+const MAX_PAYLOAD: u64 = MAX_BODY as u64 / 2;
+
+/// payload is a bincode encoded `Command`
+const RECORD_COMMAND: u8 = 1;
+
+/// the one log file of the ROC versions before segments
+const LEGACY_WAL: &str = "wal.log";
+
+struct WalState {
+    /// the LSN the next record gets, set up by read_wal at startup
+    next_lsn: u64,
+    /// the segment new records are appended to
+    active: Option<Segment>,
+    /// bytes appended since the server started, the snapshot policy watches it
+    bytes_written: u64,
+}
+
+struct Segment {
+    /// kept open in append mode for as long as the segment takes new records
+    file: File,
+    /// the length of the file up to the last record that made it in whole
+    size: u64,
+    /// records were written since the last sync
+    dirty: bool,
+    /// a failed write left bytes past size that could not be cut off yet
+    damaged: bool,
+}
+
+impl Segment {
+    fn open(path: &Path, size: u64) -> io::Result<Self> {
+        Ok(Segment {
+            file: OpenOptions::new().append(true).open(path)?,
+            size,
+            dirty: false,
+            damaged: false,
+        })
+    }
+
+    /// Appends data whole or not at all
+    ///
+    /// Part of a failed write may have reached the file, it is cut off again so that the next
+    /// record does not land behind garbage -- a restart would take that for a damaged log.
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.damaged {
+            self.truncate(self.size)?;
+        }
+        if let Err(e) = self.file.write_all(data) {
+            let _ = self.truncate(self.size);
+            return Err(e);
+        }
+        self.size += data.len() as u64;
+        self.dirty = true;
+        Ok(())
     }
 
-    // let's open the log file in append mode since we need to log into the file
-    let log_file_path = log_dir.join("wal.log");
-    let file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(log_file_path)
-        .expect("Failed to open file wal.log");
+    /// Cuts the file back to len, until that works no more records go in
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.damaged = true;
+        self.file.set_len(len)?;
+        // appends go to the end anyway, this just keeps the position in line with it
+        self.file.seek(SeekFrom::Start(len))?;
+        self.size = len;
+        self.damaged = false;
+        Ok(())
+    }
 
-    let mut writer = BufWriter::new(file);
+    fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
 
-    let serialized = serde_json::to_string(com).expect("Serialization failed!");
+static WAL: Mutex<WalState> = Mutex::new(WalState {
+    next_lsn: 1,
+    active: None,
+    bytes_written: 0,
+});
 
-    writer
-        .write_all(serialized.as_bytes())
-        .expect("Failed to write to wal.log");
+/// A record read back from the WAL
+#[derive(Debug)]
+pub(crate) struct WalRecord {
+    pub(crate) lsn: u64,
+    /// unix time in milliseconds, 0 for records from version 1 segments
+    pub(crate) timestamp: u64,
+    pub(crate) command: Command,
+}
 
-    writer
-        .write_all(b"\n")
-        .expect("Failed to write newline to wal.log");
+fn corrupt(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
 
-    writer.flush().expect("Failed to flush wal.log");
+fn segment_path(first_lsn: u64) -> PathBuf {
+    Path::new(WAL_DIR).join(format!("wal-{:020}.log", first_lsn))
 }
 
-/// helper function for reading from the WAL log
-pub(crate) fn read_wal() -> io::Result<Vec<Command>> {
-    // we gotta return a vector of all the instructions
+/// All the segments in the log directory dir as (first lsn, path), oldest first
+pub(crate) fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+
+    let dir = match fs::read_dir(dir) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(segments),
+        Err(e) => return Err(e),
+    };
+    for file in dir {
+        let path = file?.path();
+        let first_lsn = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("wal-"))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|lsn| lsn.parse::<u64>().ok());
+        if let Some(first_lsn) = first_lsn {
+            segments.push((first_lsn, path));
+        }
+    }
 
-    let file_path = Path::new("../logs/wal.log");
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
+    segments.sort();
+    Ok(segments)
+}
 
-    let mut entries = Vec::new();
+/// Starts a new, empty segment for the records from first_lsn on
+fn create_segment(first_lsn: u64) -> io::Result<Segment> {
+    fs::create_dir_all(WAL_DIR)?;
+    let path = segment_path(first_lsn);
 
-    for line_res in reader.lines() {
-        let line = line_res?;
-        let trimmed = line.trim();
-        if trimmed.is_empty() || !trimmed.starts_with('{') {
-            continue;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    file.write_all(&segment_header()?)?;
+    file.sync_all()?;
+    if CONFIG.fsync != FsyncPolicy::Never {
+        // the new file itself has to survive a power loss too
+        File::open(WAL_DIR)?.sync_all()?;
+    }
+
+    eprintln!("WAL: started segment {:?}", path);
+    Segment::open(&path, SEGMENT_HEADER as u64)
+}
+
+/// the header of a segment written with the current settings
+fn segment_header() -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(SEGMENT_HEADER);
+    header.extend_from_slice(SEGMENT_MAGIC);
+    header.extend_from_slice(&SEGMENT_VERSION.to_le_bytes());
+    let encrypted = codec::cipher()?.is_some();
+    header.extend_from_slice(&[codec::compression_id(CONFIG.compression), encrypted as u8]);
+    Ok(header)
+}
+
+/// Refuses a command too big to be logged, before it is handed to the writer
+pub(crate) fn check_size(command: &Command) -> Result<(), String> {
+    let size = bincode::serialized_size(command).map_err(|e| e.to_string())?;
+    if size > MAX_PAYLOAD {
+        return Err(format!(
+            "Write too large: {} bytes, at most {} bytes can be logged",
+            size, MAX_PAYLOAD
+        ));
+    }
+    Ok(())
+}
+
+/// Write Ahead Logging [WAL]
+/// append a batch of commands, each one as its own record
+///
+/// Returns the LSN of the last record once they are written, and synced if CONFIG.fsync is
+/// "always".
+pub(crate) fn append(commands: &[&Command]) -> io::Result<u64> {
+    let payloads = commands
+        .iter()
+        .map(|com| bincode::serialize(com).map_err(|e| corrupt(e.to_string())))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    write_batch(&payloads)
+}
+
+/// Gives every record in batch the next LSN and appends them to the log with a single write
+///
+/// Either the whole batch is in the log and the LSNs are used up, or none of it is -- so a write
+/// reported as failed never comes back on a replay.
+fn write_batch(batch: &[Vec<u8>]) -> io::Result<u64> {
+    // hold the lock until the batch is written so the LSNs land in the log in order
+    let mut wal = WAL.lock().unwrap();
+    let wal = &mut *wal;
+    if batch.is_empty() {
+        return Ok(wal.next_lsn - 1);
+    }
+    let timestamp = store::now_millis();
+    let cipher = codec::cipher()?;
+
+    // move on to a new segment before writing anything, a batch never spans two -- so a
+    // segment can go past the configured size by up to one batch
+    let full = wal
+        .active
+        .as_ref()
+        .is_none_or(|segment| segment.size >= CONFIG.wal_segment_size);
+    if full {
+        if let Some(old) = wal.active.as_mut() {
+            // finish the old segment, its unsynced records must not be left behind
+            if CONFIG.fsync != FsyncPolicy::Never {
+                old.sync()?;
+            }
         }
-        match serde_json::from_str::<Command>(&line) {
-            Ok(cmd) => entries.push(cmd),
-            Err(e) => eprintln!("Failed to parse command from WAL: {}", e),
+        wal.active = Some(create_segment(wal.next_lsn)?);
+    }
+
+    let mut buf = Vec::new();
+    let mut lsn = wal.next_lsn;
+    for payload in batch {
+        let payload = codec::encode(payload, CONFIG.compression, cipher, &lsn.to_le_bytes())?;
+        buf.extend(encode_record(lsn, timestamp, RECORD_COMMAND, &payload)?);
+        lsn += 1;
+    }
+
+    let segment = wal.active.as_mut().unwrap();
+    let before = segment.size;
+    segment.append(&buf)?;
+    if CONFIG.fsync == FsyncPolicy::Always {
+        if let Err(e) = segment.sync() {
+            // the batch may or may not be on disk, take it back out so that it is not
+            let _ = segment.truncate(before);
+            return Err(e);
         }
     }
-    Ok(entries)
+
+    wal.bytes_written += buf.len() as u64;
+    wal.next_lsn = lsn;
+    Ok(lsn - 1)
 }
 
-/// Save a health_checkpoint to file health_checkpoints.log
-///
-/// "CLEAN" is stored as "0"
-/// "DIRTY" is stored as "1"
-pub(crate) fn save_checkpoint(msg: String) {
-    eprintln!("Saving checkpoint: {:?}", msg);
+/// Syncs the records written so far to disk
+pub(crate) fn sync_wal() -> io::Result<()> {
+    match WAL.lock().unwrap().active.as_mut() {
+        Some(segment) => segment.sync(),
+        None => Ok(()),
+    }
+}
 
-    let file_path = Path::new("logs/health_checkpoints.log");
+/// Starts the thread syncing the WAL once a second, when CONFIG.fsync asks for it
+pub(crate) fn start_fsync_thread() {
+    if CONFIG.fsync != FsyncPolicy::EverySec {
+        return;
+    }
 
-    let file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .append(false)
-        .open(file_path)
-        .expect("Failed to open the check file!");
-
-    let flag: u8 = if msg.eq_ignore_ascii_case("CLEAN") {
-        0
-    } else {
-        1
-    };
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(1));
 
-    let mut writer = BufWriter::new(file);
+        if let Err(e) = sync_wal() {
+            eprintln!("WAL: fsync failed: {}", e);
+        }
+    });
+}
 
-    writer
-        .write_all(&[flag])
-        .expect("Failed to write the flag into the health_checkpoint.log file");
-    eprintln!(
-        "Successfully written the flag: {:?} for {:?} into the file!",
-        flag, msg
-    );
-    writer.flush().expect("Failed to flush the writer!");
+/// The LSN of the last record written to the WAL, 0 if there never was one
+pub(crate) fn last_lsn() -> u64 {
+    WAL.lock().unwrap().next_lsn - 1
+}
+
+/// How many bytes went into the WAL since the server started
+pub(crate) fn bytes_written() -> u64 {
+    WAL.lock().unwrap().bytes_written
 }
 
-/// Retrieve the last saved checkpoint for recovery checking
+/// Makes sure the next record gets at least LSN lsn
+pub(crate) fn advance_lsn(lsn: u64) {
+    let mut wal = WAL.lock().unwrap();
+    wal.next_lsn = wal.next_lsn.max(lsn);
+}
+
+/// Frames a record, refusing one that reading it back would take for garbage
+fn encode_record(lsn: u64, timestamp: u64, kind: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(17 + payload.len());
+    body.extend_from_slice(&lsn.to_le_bytes());
+    body.extend_from_slice(&timestamp.to_le_bytes());
+    body.push(kind);
+    body.extend_from_slice(payload);
+    if body.len() > MAX_BODY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "a record of {} bytes is over the limit of {} bytes",
+                body.len(),
+                MAX_BODY
+            ),
+        ));
+    }
+
+    let mut record = Vec::with_capacity(RECORD_HEADER + body.len());
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    record.extend_from_slice(&body);
+    Ok(record)
+}
+
+/// Reads all the records from the WAL segments, oldest first
 ///
-/// returns Option<String>   
+/// A record cut short at the end of the newest segment is what a crash in the middle of a write
+/// leaves behind -- it is reported and truncated away. Any other bad record means the log itself
+/// is damaged, that is an error since replaying around it would lose writes.
 ///
-/// Some("CLEAN") or Some("DIRTY")
-pub(crate) fn get_health_checkpoint() -> Option<String> {
-    eprintln!("getting health_checkpoint");
+/// Also makes sure new records continue after the highest LSN in the log.
+pub(crate) fn read_wal() -> io::Result<Vec<WalRecord>> {
+    convert_legacy_wal(Path::new(WAL_DIR))?;
+    let segments = list_segments(Path::new(WAL_DIR))?;
 
-    let file_path = Path::new("logs/health_checkpoints.log");
+    let mut entries = Vec::new();
+    let mut last_lsn = 0;
+    let mut active = None;
 
-    let data = match std::fs::read(file_path) {
-        Ok(data) => data,
-        Err(e) => {
+    for (i, (first_lsn, path)) in segments.iter().enumerate() {
+        let newest = i + 1 == segments.len();
+        let scan = scan_segment(path, *first_lsn, newest, &mut last_lsn)?;
+
+        if let Some(msg) = &scan.torn {
             eprintln!(
-                "encountered error: {} while reading data from the checkpoint file!",
-                e
+                "WAL: torn write at offset {} of {:?} ({}), cutting it off",
+                scan.valid_len, path, msg
             );
-            return None;
+            repair_segment(path, &scan)?;
         }
+        if newest && scan.valid_len > 0 {
+            // only keep appending to it if it is written the way records are written now
+            let current = scan.version == SEGMENT_VERSION
+                && scan.compression == CONFIG.compression
+                && scan.encrypted == codec::cipher()?.is_some();
+            if current {
+                active = Some(Segment::open(path, scan.valid_len)?);
+            } else if scan.records.is_empty() {
+                // nothing in it, make way for a new segment taking over its first LSN
+                fs::remove_file(path)?;
+            }
+        }
+        entries.extend(scan.records);
+    }
+
+    let mut wal = WAL.lock().unwrap();
+    // an empty segment still tells where the LSNs were at
+    let newest_first = segments.last().map_or(1, |(first_lsn, _)| *first_lsn);
+    wal.next_lsn = wal.next_lsn.max(last_lsn + 1).max(newest_first);
+    wal.active = active;
+
+    eprintln!(
+        "WAL: read {} records from {} segments up to LSN {}",
+        entries.len(),
+        segments.len(),
+        last_lsn
+    );
+    Ok(entries)
+}
+
+/// A line of the old logs/wal.log -- every command went in there, reads included, with values
+/// that were plain unsigned numbers
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Store {
+        key: String,
+        value: u64,
+    },
+    Update {
+        key: String,
+        value: u64,
+    },
+    Delete {
+        key: String,
+    },
+    Ping,
+    Shutdown,
+    Crash,
+    Fetch(IgnoredAny),
+    Range(IgnoredAny),
+    List(IgnoredAny),
+    #[serde(rename = "ERR")]
+    Err(IgnoredAny),
+}
+
+/// Turns the logs/wal.log of an older ROC into the first segment of the WAL in dir, once
+///
+/// The old server cleared that file with every snapshot, so it holds the writes on top of
+/// snapshots.json, which loads as LSN 0 -- its writes become LSN 1 onwards. The file itself is
+/// deleted once the segment is on disk, it is plaintext and would stay so even with a key file.
+/// Next to segments it can not be placed anymore, that is an error.
+fn convert_legacy_wal(dir: &Path) -> io::Result<()> {
+    let path = dir.join(LEGACY_WAL);
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
     };
+    if !list_segments(dir)?.is_empty() {
+        return Err(io::Error::other(format!(
+            "{:?} was written by an older version of ROC and there is a newer WAL next to it -- \
+             move {:?} away if its writes are in the store already",
+            path, path
+        )));
+    }
 
-    if data.is_empty() {
-        eprintln!("Empty health_checkpoints.log file!");
-        return None;
+    let lines: Vec<&str> = data.split_inclusive('\n').collect();
+    let mut commands = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let bad_line = |msg: String| corrupt(format!("{:?} line {}: {}", path, i + 1, msg));
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let legacy = match serde_json::from_str::<LegacyCommand>(line.trim()) {
+            Ok(legacy) => legacy,
+            // a crash while the last line was written
+            Err(_) if i + 1 == lines.len() && !line.ends_with('\n') => {
+                eprintln!("WAL: dropping the torn last line of {:?}", path);
+                break;
+            }
+            Err(e) => return Err(bad_line(e.to_string())),
+        };
+        commands.push(match legacy {
+            LegacyCommand::Store { key, value } => Command::Store {
+                key,
+                value: value::from_legacy(value).map_err(bad_line)?,
+                expires_at: None,
+            },
+            LegacyCommand::Update { key, value } => Command::Update {
+                key,
+                value: value::from_legacy(value).map_err(bad_line)?,
+            },
+            LegacyCommand::Delete { key } => Command::Delete { key },
+            _ => continue,
+        });
+    }
+
+    if !commands.is_empty() {
+        // the old log has no times, so the records get 0 like those of version 1 segments
+        let cipher = codec::cipher()?;
+        let mut segment = segment_header()?;
+        for (lsn, command) in (1u64..).zip(&commands) {
+            let payload = bincode::serialize(command).map_err(|e| corrupt(e.to_string()))?;
+            let payload = codec::encode(&payload, CONFIG.compression, cipher, &lsn.to_le_bytes())?;
+            segment.extend(encode_record(lsn, 0, RECORD_COMMAND, &payload)?);
+        }
+
+        // write to the side and rename, a crash must not leave half a segment behind
+        let target = dir.join(format!("wal-{:020}.log", 1));
+        let tmp = target.with_extension("log.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&segment)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &target)?;
+        File::open(dir)?.sync_all()?;
     }
 
-    match data[0] {
-        0 => {
-            eprintln!("Found CLEAN flag! No recovery needed!");
-            Some("CLEAN".to_string())
+    fs::remove_file(&path)?;
+    File::open(dir)?.sync_all()?;
+    eprintln!(
+        "WAL: converted {} writes from {:?}, an older version of ROC wrote it",
+        commands.len(),
+        path
+    );
+    Ok(())
+}
+
+/// What is in a segment file
+#[derive(Debug)]
+pub(crate) struct SegmentScan {
+    pub(crate) version: u16,
+    pub(crate) compression: Compression,
+    pub(crate) encrypted: bool,
+    pub(crate) records: Vec<WalRecord>,
+    /// how much of the file holds complete records (or the header), a torn write starts here
+    pub(crate) valid_len: u64,
+    /// what is wrong with the torn write at the end, if there is one
+    pub(crate) torn: Option<String>,
+}
+
+/// Reads the records of one segment, without changing the file
+///
+/// Torn records are only expected at the end of the newest segment, the others were complete
+/// before the next one was started -- anything wrong in those is an error. last_lsn carries the
+/// LSN order check from one segment to the next.
+pub(crate) fn scan_segment(
+    path: &Path,
+    first_lsn: u64,
+    newest: bool,
+    last_lsn: &mut u64,
+) -> io::Result<SegmentScan> {
+    let data = fs::read(path)?;
+    let damaged = |pos: usize, msg: String| {
+        corrupt(format!(
+            "WAL segment {:?} corrupted at offset {}: {}",
+            path, pos, msg
+        ))
+    };
+    let mut scan = SegmentScan {
+        version: 0,
+        compression: Compression::None,
+        encrypted: false,
+        records: Vec::new(),
+        valid_len: 0,
+        torn: None,
+    };
+
+    let incomplete = |scan: SegmentScan| {
+        if !newest {
+            return Err(damaged(0, "incomplete segment header".to_string()));
         }
-        1 => {
-            eprintln!("Found DIRTY flag. Recovery needed!");
-            Some("DIRTY".to_string())
+        Ok(SegmentScan {
+            torn: Some("incomplete segment header".to_string()),
+            ..scan
+        })
+    };
+
+    if data.len() < SEGMENT_PREFIX {
+        return incomplete(scan);
+    }
+    if &data[..6] != SEGMENT_MAGIC {
+        return Err(damaged(0, "not a WAL segment".to_string()));
+    }
+    let version = u16::from_le_bytes(data[6..8].try_into().unwrap());
+    // where the type byte sits in a record body
+    let (type_at, header) = match version {
+        1 => (8, SEGMENT_PREFIX),
+        2 => (16, SEGMENT_PREFIX),
+        SEGMENT_VERSION => (16, SEGMENT_HEADER),
+        _ => return Err(damaged(0, format!("unsupported WAL version {}", version))),
+    };
+    if data.len() < header {
+        return incomplete(scan);
+    }
+    scan.version = version;
+
+    if version >= 3 {
+        scan.compression = codec::compression_from_id(data[8])
+            .ok_or_else(|| damaged(8, format!("unknown compression {}", data[8])))?;
+        scan.encrypted = match data[9] {
+            0 => false,
+            1 => true,
+            other => return Err(damaged(9, format!("bad encryption flag {}", other))),
+        };
+    }
+    let cipher = match scan.encrypted {
+        true => Some(codec::required_cipher()?),
+        false => None,
+    };
+
+    let mut pos = header;
+    while pos < data.len() {
+        let (body, next) = match parse_record(&data, pos) {
+            Ok(parsed) => parsed,
+            Err(msg) if newest && is_torn_tail(&data, pos) => {
+                scan.torn = Some(format!("{}, {} bytes", msg, data.len() - pos));
+                break;
+            }
+            Err(msg) => return Err(damaged(pos, msg)),
+        };
+
+        if body.len() <= type_at {
+            return Err(damaged(pos, "record too short".to_string()));
         }
-        _other => {
-            eprintln!("Invalid flag found!");
-            None
+        let lsn = u64::from_le_bytes(body[..8].try_into().unwrap());
+        let timestamp = match version {
+            1 => 0,
+            _ => u64::from_le_bytes(body[8..16].try_into().unwrap()),
+        };
+        if lsn <= *last_lsn || lsn < first_lsn {
+            return Err(damaged(
+                pos,
+                format!("LSN {} out of order after LSN {}", lsn, last_lsn),
+            ));
         }
+
+        let payload = codec::decode(
+            &body[type_at + 1..],
+            scan.compression,
+            cipher,
+            &lsn.to_le_bytes(),
+        )
+        .map_err(|e| damaged(pos, e.to_string()))?;
+        let command = match body[type_at] {
+            RECORD_COMMAND => bincode::deserialize::<Command>(&payload)
+                .map_err(|e| damaged(pos, e.to_string()))?,
+            other => return Err(damaged(pos, format!("unknown record type {}", other))),
+        };
+
+        scan.records.push(WalRecord {
+            lsn,
+            timestamp,
+            command,
+        });
+        *last_lsn = lsn;
+        pos = next;
     }
+
+    scan.valid_len = pos as u64;
+    Ok(scan)
 }
 
-pub(crate) fn clear_wal() -> io::Result<()> {
-    let file_path = Path::new("logs/wal.log");
+/// Cuts the torn write found by scan_segment off the end of the segment
+///
+/// A segment that did not even get its header out is removed, the next write starts it over.
+pub(crate) fn repair_segment(path: &Path, scan: &SegmentScan) -> io::Result<()> {
+    if scan.torn.is_none() {
+        return Ok(());
+    }
+    if scan.valid_len < SEGMENT_PREFIX as u64 {
+        return fs::remove_file(path);
+    }
 
-    let _file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(file_path)
-        .expect("failed to open the file!");
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(scan.valid_len)?;
+    file.sync_all()
+}
+
+/// Deletes the segments whose records all have an LSN of at most lsn
+///
+/// Call it only once a snapshot holding everything up to lsn is safely on disk.
+pub(crate) fn remove_covered_segments(lsn: u64) -> io::Result<()> {
+    // hold the lock so a rotation can not happen underneath
+    let mut wal = WAL.lock().unwrap();
+
+    // when the snapshot covers all of the log, move on to a fresh segment so the rest can go --
+    // the empty segment keeps the next LSN around for a restart
+    let has_records = wal
+        .active
+        .as_ref()
+        .is_some_and(|segment| segment.size > SEGMENT_HEADER as u64);
+    if wal.next_lsn <= lsn + 1 && has_records {
+        wal.active = Some(create_segment(wal.next_lsn)?);
+    }
+
+    let segments = list_segments(Path::new(WAL_DIR))?;
+    let mut removed = 0;
+    for pair in segments.windows(2) {
+        // every record in a segment comes before the first one of the next segment
+        if pair[1].0 <= lsn + 1 {
+            fs::remove_file(&pair[0].1)?;
+            removed += 1;
+        }
+    }
 
+    if removed > 0 {
+        eprintln!(
+            "WAL: removed {} segments covered up to LSN {}",
+            removed, lsn
+        );
+    }
     Ok(())
 }
+
+/// Checks the framing and checksum of the record at pos, returns its body and where the next
+/// record starts
+fn parse_record(data: &[u8], pos: usize) -> Result<(&[u8], usize), String> {
+    let header = data
+        .get(pos..pos + RECORD_HEADER)
+        .ok_or_else(|| "incomplete header".to_string())?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    if !(MIN_BODY..=MAX_BODY).contains(&len) {
+        return Err(format!("bad record length {}", len));
+    }
+
+    let start = pos + RECORD_HEADER;
+    let body = data
+        .get(start..start + len)
+        .ok_or_else(|| "incomplete record".to_string())?;
+    if crc32fast::hash(body) != crc {
+        return Err("checksum mismatch".to_string());
+    }
+
+    Ok((body, start + len))
+}
+
+/// Whether the bad record at pos can only be the remains of an interrupted append
+///
+/// That is the case when it runs up to the end of the file (or claims to run past it), or when
+/// nothing but zeroes follow, which is how some filesystems leave a half written block. Either
+/// way no good record may come after it -- a damaged length can make a record in the middle
+/// claim the rest of the file, and cutting that off would drop every record behind it.
+fn is_torn_tail(data: &[u8], pos: usize) -> bool {
+    let rest = &data[pos..];
+    if rest.iter().all(|b| *b == 0) {
+        return true;
+    }
+
+    if rest.len() >= RECORD_HEADER {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        if !(MIN_BODY..=MAX_BODY).contains(&len) || RECORD_HEADER + len < rest.len() {
+            return false;
+        }
+    }
+    (pos + 1..data.len()).all(|at| parse_record(data, at).is_err())
+}
+
+/// Moves every segment into dir, the log starts over empty while the LSNs keep counting
+///
+/// For point in time recovery: the records past the recovery point must not be replayed on top
+/// of the recovered state, but they should not be thrown away either.
+pub(crate) fn archive_segments(dir: &Path) -> io::Result<usize> {
+    let mut wal = WAL.lock().unwrap();
+
+    let segments = list_segments(Path::new(WAL_DIR))?;
+    fs::create_dir_all(dir)?;
+    for (_, path) in &segments {
+        if let Some(name) = path.file_name() {
+            fs::rename(path, dir.join(name))?;
+        }
+    }
+
+    wal.active = None;
+    Ok(segments.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rocs-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// a segment with a record for each of the keys, LSN 1 onwards, and where each record ends
+    fn segment(keys: &[&str]) -> (Vec<u8>, Vec<usize>) {
+        let cipher = codec::cipher().unwrap();
+        let mut data = segment_header().unwrap();
+        let mut ends = Vec::new();
+
+        for (lsn, key) in (1u64..).zip(keys) {
+            let command = Command::Delete {
+                key: key.to_string(),
+            };
+            let payload = bincode::serialize(&command).unwrap();
+            let payload =
+                codec::encode(&payload, CONFIG.compression, cipher, &lsn.to_le_bytes()).unwrap();
+            data.extend(encode_record(lsn, 1000 + lsn, RECORD_COMMAND, &payload).unwrap());
+            ends.push(data.len());
+        }
+        (data, ends)
+    }
+
+    fn scan(path: &Path, newest: bool) -> io::Result<SegmentScan> {
+        scan_segment(path, 1, newest, &mut 0)
+    }
+
+    fn lsns(scan: &SegmentScan) -> Vec<u64> {
+        scan.records.iter().map(|record| record.lsn).collect()
+    }
+
+    #[test]
+    fn cuts_a_torn_write_off_the_newest_segment() {
+        let dir = test_dir("torn");
+        let path = dir.join("wal-00000000000000000001.log");
+        let (data, ends) = segment(&["a", "b", "c", "d"]);
+
+        // half a record, and a record followed by a block of zeroes
+        for torn in [
+            data[..ends[3] - 5].to_vec(),
+            [&data[..ends[2]], &[0; 100]].concat(),
+        ] {
+            fs::write(&path, &torn).unwrap();
+
+            let found = scan(&path, true).unwrap();
+            assert_eq!(lsns(&found), vec![1, 2, 3]);
+            assert_eq!(found.valid_len, ends[2] as u64);
+            assert!(found.torn.is_some());
+            assert_eq!(found.records[2].timestamp, 1003);
+
+            // only the newest segment can have been cut short by a crash
+            let err = scan(&path, false).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            repair_segment(&path, &found).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), ends[2] as u64);
+            let repaired = scan(&path, true).unwrap();
+            assert_eq!(lsns(&repaired), vec![1, 2, 3]);
+            assert!(repaired.torn.is_none());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_checksum_mismatch_is_torn_only_at_the_end() {
+        let dir = test_dir("crc");
+        let path = dir.join("wal-00000000000000000001.log");
+        let (data, ends) = segment(&["a", "b", "c"]);
+
+        // the last record, which the crash may have hit halfway
+        let mut last = data.clone();
+        last[ends[2] - 1] ^= 0xff;
+        fs::write(&path, &last).unwrap();
+        let found = scan(&path, true).unwrap();
+        assert_eq!(lsns(&found), vec![1, 2]);
+        assert_eq!(found.valid_len, ends[1] as u64);
+        assert!(found.torn.unwrap().contains("checksum"));
+
+        // a record with more behind it was complete once, the log is damaged
+        let mut middle = data.clone();
+        middle[ends[1] - 1] ^= 0xff;
+        fs::write(&path, &middle).unwrap();
+        let err = scan(&path, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"));
+
+        // or a length that makes a record in the middle reach past the end
+        let mut long = data.clone();
+        long[ends[0]..ends[0] + 4].copy_from_slice(&100_000u32.to_le_bytes());
+        fs::write(&path, &long).unwrap();
+        let err = scan(&path, true).unwrap_err();
+        assert!(err.to_string().contains("incomplete record"));
+
+        // same for a length that does not fit any record
+        let mut framing = data.clone();
+        framing[ends[0]..ends[0] + 4].copy_from_slice(&3u32.to_le_bytes());
+        fs::write(&path, &framing).unwrap();
+        let err = scan(&path, true).unwrap_err();
+        assert!(err.to_string().contains("bad record length"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_record_it_could_not_read_back() {
+        assert!(encode_record(1, 0, RECORD_COMMAND, &vec![0; MAX_BODY - 17]).is_ok());
+        let err = encode_record(1, 0, RECORD_COMMAND, &vec![0; MAX_BODY - 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let command = Command::Delete {
+            key: "k".repeat(MAX_PAYLOAD as usize),
+        };
+        assert!(check_size(&command).unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn converts_a_baseline_wal_log() {
+        let dir = test_dir("legacy");
+        // written by the baseline store_log, a crash cut off the last line
+        fs::write(
+            dir.join(LEGACY_WAL),
+            concat!(
+                "{\"Store\":{\"key\":\"a\",\"value\":12}}\n",
+                "{\"Fetch\":{\"key\":\"a\",\"value\":12}}\n",
+                "\"Ping\"\n",
+                "{\"Update\":{\"key\":\"a\",\"value\":13}}\n",
+                "{\"Range\":{\"start\":0,\"end\":20,\"result\":[[\"a\",13]]}}\n",
+                "{\"Store\":{\"key\":\"b\",\"value\":1}}\n",
+                "{\"Delete\":{\"key\":\"b\"}}\n",
+                "{\"ERR\":{\"msg\":\"unknown command\"}}\n",
+                "{\"Store\":{\"key\":\"c\",\"val",
+            ),
+        )
+        .unwrap();
+
+        convert_legacy_wal(&dir).unwrap();
+        assert!(!dir.join(LEGACY_WAL).exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let segments = list_segments(&dir).unwrap();
+        assert_eq!(segments.len(), 1);
+        let found = scan(&segments[0].1, true).unwrap();
+        assert_eq!(lsns(&found), vec![1, 2, 3, 4]);
+        let commands: Vec<String> = found
+            .records
+            .iter()
+            .map(|record| format!("{:?}", record.command))
+            .collect();
+        let expected = [
+            Command::Store {
+                key: "a".to_string(),
+                value: Value::Int(12),
+                expires_at: None,
+            },
+            Command::Update {
+                key: "a".to_string(),
+                value: Value::Int(13),
+            },
+            Command::Store {
+                key: "b".to_string(),
+                value: Value::Int(1),
+                expires_at: None,
+            },
+            Command::Delete {
+                key: "b".to_string(),
+            },
+        ];
+        assert_eq!(
+            commands,
+            expected
+                .iter()
+                .map(|c| format!("{:?}", c))
+                .collect::<Vec<_>>()
+        );
+
+        // done once, the next start leaves it alone
+        convert_legacy_wal(&dir).unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), segments);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_baseline_wal_log_it_can_not_place() {
+        let dir = test_dir("legacy-refused");
+
+        // next to a newer WAL
+        fs::write(dir.join(LEGACY_WAL), "{\"Delete\":{\"key\":\"a\"}}\n").unwrap();
+        fs::write(dir.join("wal-00000000000000000001.log"), segment(&["a"]).0).unwrap();
+        let err = convert_legacy_wal(&dir).unwrap_err();
+        assert!(err.to_string().contains("older version of ROC"));
+        assert!(dir.join(LEGACY_WAL).exists());
+
+        // with a line in the middle that is not a command
+        fs::remove_file(dir.join("wal-00000000000000000001.log")).unwrap();
+        fs::write(
+            dir.join(LEGACY_WAL),
+            "{\"Delete\":{\"key\":\"a\"}}\ngarbage\n{\"Delete\":{\"key\":\"b\"}}\n",
+        )
+        .unwrap();
+        let err = convert_legacy_wal(&dir).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        assert!(list_segments(&dir).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
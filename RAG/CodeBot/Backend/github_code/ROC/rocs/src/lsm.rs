@@ -0,0 +1,1428 @@
+// ROC/rocs/src/lsm.rs
+
+// An LSM tree storage engine
+//
+// Writes land in an in-memory memtable (which is what the WAL replays into after a crash). Once
+// the memtable grows past MEMTABLE_LIMIT it is frozen -- at the end of a WAL record, never in the
+// middle of one, since the MANIFEST records the LSN the tables hold and a restart replays the WAL
+// after it -- and a fresh one takes the writes. The background thread writes the frozen memtable
+// out as an immutable, sorted SSTable into level 0, reads go to it until then. A snapshot's
+// checkpoint freezes the memtable as well and flushes it in its job, outside the store lock, but
+// leaves a small one alone -- the WAL keeps it until it is flushed. The background thread also
+// merges tables down the levels:
+//
+//  L0  -- freshly flushed tables, their key ranges may overlap, newest first
+//  L1+ -- tables with disjoint key ranges sorted by key, every level LEVEL_MULTIPLIER times
+//         bigger than the one above it
+//
+// SSTable layout:
+//
+//  [data block]...[index][bloom filter][footer]
+//
+//  data block  -- records (key len u32, key, tag u8, value len u32, bincode `Entry`), ~BLOCK_SIZE
+//  index       -- bincode Vec<BlockHandle>, the last key and position of every block
+//  bloom       -- bincode `Bloom` over all the keys in the table
+//  footer      -- index offset, index len, bloom offset, bloom len (u64 each), then MAGIC
+//
+// The index and bloom filter of every table are kept in memory, the data blocks are read from
+// disk when needed. The MANIFEST file lists which table sits on which level.
+
+use crate::engine::{Checkpoint, Snapshot, StorageEngine};
+use crate::store::Entry;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+const MEMTABLE_LIMIT: usize = 4 * 1024 * 1024;
+// frozen memtables waiting for the background thread -- past this many the writes stop and flush
+// one themselves, so that memory does not run away while the disk can not keep up
+const MAX_FROZEN: usize = 4;
+// a checkpoint leaves a memtable smaller than this to the WAL, unless it holds this many records
+// of the WAL -- the WAL can only be trimmed up to what the tables hold
+const CHECKPOINT_MIN_SIZE: usize = MEMTABLE_LIMIT / 4;
+const CHECKPOINT_MAX_RECORDS: u64 = 100_000;
+const BLOCK_SIZE: usize = 4 * 1024;
+// compaction writes tables of about this size
+const TABLE_SIZE: u64 = 2 * 1024 * 1024;
+
+const L0_COMPACTION_TRIGGER: usize = 4;
+const L1_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const LEVEL_MULTIPLIER: u64 = 10;
+const MAX_LEVELS: usize = 7;
+
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+
+const MAGIC: &[u8; 8] = b"ROCSSTAB";
+const FOOTER_SIZE: u64 = 4 * 8 + 8;
+
+const TAG_TOMBSTONE: u8 = 0;
+const TAG_VALUE: u8 = 1;
+
+/// a key with its entry, None marks a deleted key
+type Record = (String, Option<Entry>);
+
+type RecordIter = Box<dyn Iterator<Item = io::Result<Record>> + Send>;
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn past_end(key: &str, end: &Bound<String>) -> bool {
+    match end {
+        Bound::Included(end) => key > end.as_str(),
+        Bound::Excluded(end) => key >= end.as_str(),
+        Bound::Unbounded => false,
+    }
+}
+
+fn before_start(key: &str, start: &Bound<String>) -> bool {
+    match start {
+        Bound::Included(start) => key < start.as_str(),
+        Bound::Excluded(start) => key <= start.as_str(),
+        Bound::Unbounded => false,
+    }
+}
+
+fn owned_bound(bound: Bound<&str>) -> Bound<String> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_string()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_string()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// FNV-1a -- the filters are stored on disk, so the hash must never change between builds
+fn fnv1a(key: &[u8], seed: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    fn new(keys: usize) -> Self {
+        let words = (keys * BLOOM_BITS_PER_KEY).div_ceil(64).max(1);
+        Bloom {
+            bits: vec![0; words],
+            hashes: BLOOM_HASHES,
+        }
+    }
+
+    /// positions of key in the filter, double hashing over two FNV variants
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let total = self.bits.len() as u64 * 64;
+        let h1 = fnv1a(key.as_bytes(), 0);
+        let h2 = fnv1a(key.as_bytes(), 0x9e37_79b9_7f4a_7c15) | 1;
+
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % total) as usize)
+    }
+
+    fn insert(&mut self, key: &str) {
+        let positions: Vec<usize> = self.positions(key).collect();
+        for bit in positions {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// false means the key is definitely not in the table
+    fn may_contain(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// An immutable sorted table on disk
+struct SsTable {
+    id: u64,
+    path: PathBuf,
+    size: u64,
+    first_key: String,
+    last_key: String,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    file: Mutex<File>,
+}
+
+impl SsTable {
+    fn open(id: u64, path: PathBuf) -> io::Result<Self> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_SIZE {
+            return Err(corrupt("sstable too small"));
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE))?;
+        file.read_exact(&mut footer)?;
+        if &footer[32..40] != MAGIC {
+            return Err(corrupt("not an sstable"));
+        }
+        let field = |i: usize| u64::from_le_bytes(footer[i * 8..i * 8 + 8].try_into().unwrap());
+        let (index_offset, index_len, bloom_offset, bloom_len) =
+            (field(0), field(1), field(2), field(3));
+
+        let index: Vec<BlockHandle> =
+            bincode::deserialize(&read_at(&mut file, index_offset, index_len)?)
+                .map_err(|e| corrupt(&e.to_string()))?;
+        let bloom: Bloom = bincode::deserialize(&read_at(&mut file, bloom_offset, bloom_len)?)
+            .map_err(|e| corrupt(&e.to_string()))?;
+
+        let (first_key, last_key) = match (index.first(), index.last()) {
+            (Some(first), Some(last)) => {
+                let first_block = decode_block(&read_at(&mut file, first.offset, first.len)?)?;
+                (first_block[0].0.clone(), last.last_key.clone())
+            }
+            _ => return Err(corrupt("empty sstable")),
+        };
+
+        Ok(SsTable {
+            id,
+            path,
+            size,
+            first_key,
+            last_key,
+            index,
+            bloom,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn read_block(&self, i: usize) -> io::Result<Vec<Record>> {
+        let handle = &self.index[i];
+        let mut file = self.file.lock().unwrap();
+        decode_block(&read_at(&mut file, handle.offset, handle.len)?)
+    }
+
+    /// index of the first block that can hold key
+    fn block_for(&self, key: &str) -> usize {
+        self.index
+            .partition_point(|handle| handle.last_key.as_str() < key)
+    }
+
+    /// Some(None) if the key was deleted in this table, None if the table knows nothing about it
+    fn get(&self, key: &str) -> io::Result<Option<Option<Entry>>> {
+        if key < self.first_key.as_str()
+            || key > self.last_key.as_str()
+            || !self.bloom.may_contain(key)
+        {
+            return Ok(None);
+        }
+
+        let i = self.block_for(key);
+        if i >= self.index.len() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .read_block(i)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, entry)| entry))
+    }
+
+    fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key.as_str() <= last && self.last_key.as_str() >= first
+    }
+
+    /// iterates over the records from the bound on, upwards -- or downwards with rev
+    fn iter(self: &Arc<Self>, from: &Bound<String>, rev: bool) -> TableIter {
+        let block = match from {
+            Bound::Included(key) | Bound::Excluded(key) if rev => {
+                (self.block_for(key) + 1).min(self.index.len())
+            }
+            Bound::Unbounded if rev => self.index.len(),
+            Bound::Included(key) | Bound::Excluded(key) => self.block_for(key),
+            Bound::Unbounded => 0,
+        };
+
+        TableIter {
+            table: self.clone(),
+            block,
+            records: Vec::new().into_iter(),
+            from: from.clone(),
+            rev,
+        }
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn encode_record(buf: &mut Vec<u8>, key: &str, entry: &Option<Entry>) -> io::Result<()> {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+
+    match entry {
+        None => buf.push(TAG_TOMBSTONE),
+        Some(entry) => {
+            let value = bincode::serialize(entry).map_err(|e| corrupt(&e.to_string()))?;
+            buf.push(TAG_VALUE);
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&value);
+        }
+    }
+    Ok(())
+}
+
+fn decode_block(block: &[u8]) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+
+    let take = |pos: &mut usize, n: usize| -> io::Result<&[u8]> {
+        let bytes = block
+            .get(*pos..*pos + n)
+            .ok_or_else(|| corrupt("truncated sstable block"))?;
+        *pos += n;
+        Ok(bytes)
+    };
+
+    while pos < block.len() {
+        let key_len = u32::from_le_bytes(take(&mut pos, 4)?.try_into().unwrap()) as usize;
+        let key = String::from_utf8(take(&mut pos, key_len)?.to_vec())
+            .map_err(|_| corrupt("sstable key is not utf-8"))?;
+
+        let entry = match take(&mut pos, 1)?[0] {
+            TAG_TOMBSTONE => None,
+            TAG_VALUE => {
+                let len = u32::from_le_bytes(take(&mut pos, 4)?.try_into().unwrap()) as usize;
+                Some(
+                    bincode::deserialize(take(&mut pos, len)?)
+                        .map_err(|e| corrupt(&e.to_string()))?,
+                )
+            }
+            _ => return Err(corrupt("unknown record tag in sstable")),
+        };
+
+        records.push((key, entry));
+    }
+
+    Ok(records)
+}
+
+struct TableIter {
+    table: Arc<SsTable>,
+    /// the next block to read, with rev the one after it
+    block: usize,
+    /// what is left of the current block, reversed with rev
+    records: std::vec::IntoIter<Record>,
+    /// the start of the range, or its end with rev
+    from: Bound<String>,
+    rev: bool,
+}
+
+impl Iterator for TableIter {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                let outside = match self.rev {
+                    true => past_end(&record.0, &self.from),
+                    false => before_start(&record.0, &self.from),
+                };
+                if outside {
+                    continue;
+                }
+                return Some(Ok(record));
+            }
+
+            let block = match self.rev {
+                true => self.block.checked_sub(1),
+                false => Some(self.block).filter(|block| *block < self.table.index.len()),
+            };
+            let block = block?;
+            match self.table.read_block(block) {
+                Ok(mut records) => {
+                    if self.rev {
+                        records.reverse();
+                    }
+                    self.records = records.into_iter();
+                    self.block = if self.rev { block } else { block + 1 };
+                }
+                Err(e) => {
+                    self.block = if self.rev { 0 } else { self.table.index.len() };
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Writes records (sorted by key) into a new table file
+struct TableWriter {
+    path: PathBuf,
+    out: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    last_key: String,
+    index: Vec<BlockHandle>,
+    keys: Vec<String>,
+}
+
+impl TableWriter {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(TableWriter {
+            path,
+            out: BufWriter::new(file),
+            offset: 0,
+            block: Vec::new(),
+            last_key: String::new(),
+            index: Vec::new(),
+            keys: Vec::new(),
+        })
+    }
+
+    fn add(&mut self, key: &str, entry: &Option<Entry>) -> io::Result<()> {
+        encode_record(&mut self.block, key, entry)?;
+        self.last_key = key.to_string();
+        self.keys.push(key.to_string());
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        self.out.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// bytes written so far
+    fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// writes the index, bloom filter and footer and syncs the file and its directory entry
+    fn finish(mut self) -> io::Result<PathBuf> {
+        self.finish_block()?;
+
+        let mut bloom = Bloom::new(self.keys.len());
+        for key in &self.keys {
+            bloom.insert(key);
+        }
+
+        let index = bincode::serialize(&self.index).map_err(|e| corrupt(&e.to_string()))?;
+        let bloom = bincode::serialize(&bloom).map_err(|e| corrupt(&e.to_string()))?;
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + index.len() as u64;
+
+        self.out.write_all(&index)?;
+        self.out.write_all(&bloom)?;
+        for field in [
+            index_offset,
+            index.len() as u64,
+            bloom_offset,
+            bloom.len() as u64,
+        ] {
+            self.out.write_all(&field.to_le_bytes())?;
+        }
+        self.out.write_all(MAGIC)?;
+
+        self.out.flush()?;
+        self.out.get_ref().sync_all()?;
+        // a manifest may list the table from here on, it has to be there after a power loss
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(self.path)
+    }
+}
+
+/// Merges record streams sorted by key into one
+///
+/// Sources are given newest first -- when several of them hold a key, the newest one wins. With
+/// rev the sources and the merge go from the largest key down.
+struct MergeIter {
+    sources: Vec<std::iter::Peekable<RecordIter>>,
+    /// where the merge ends, the start of the range with rev
+    stop: Bound<String>,
+    rev: bool,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<RecordIter>, stop: Bound<String>, rev: bool) -> Self {
+        MergeIter {
+            sources: sources.into_iter().map(|s| s.peekable()).collect(),
+            stop,
+            rev,
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // find the smallest key at the head of any source (the largest with rev), the first
+        // source holding it wins
+        let rev = self.rev;
+        let mut first: Option<(usize, String)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _)))
+                    if first
+                        .as_ref()
+                        .is_none_or(|(_, best)| if rev { key > best } else { key < best }) =>
+                {
+                    first = Some((i, key.clone()));
+                }
+                Some(Err(_)) => return source.next(),
+                _ => {}
+            }
+        }
+
+        let (winner, key) = first?;
+        let done = match rev {
+            true => before_start(&key, &self.stop),
+            false => past_end(&key, &self.stop),
+        };
+        if done {
+            return None;
+        }
+
+        // older versions of the same key are shadowed
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            if i != winner && matches!(source.peek(), Some(Ok((k, _))) if *k == key) {
+                source.next();
+            }
+        }
+        self.sources[winner].next()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+    /// the LSN of the WAL the tables hold
+    #[serde(default)]
+    lsn: u64,
+}
+
+struct State {
+    memtable: BTreeMap<String, Option<Entry>>,
+    memtable_size: usize,
+    /// memtables on their way into L0, newest first
+    frozen: Vec<Frozen>,
+    levels: Vec<Vec<Arc<SsTable>>>,
+    /// the LSN of the WAL the memtables and the tables hold together
+    lsn: u64,
+    /// the LSN of the WAL the tables hold, as written to the manifest
+    persisted_lsn: u64,
+}
+
+/// A memtable that takes no more writes, until it is written out as a table
+struct Frozen {
+    records: Arc<BTreeMap<String, Option<Entry>>>,
+    /// the LSN of the WAL it holds, with everything before it
+    lsn: u64,
+}
+
+/// Walks a frozen memtable from a bound on, holding on to it instead of copying it
+struct FrozenIter {
+    records: Arc<BTreeMap<String, Option<Entry>>>,
+    /// where the next record is looked for, from above with rev
+    next: Bound<String>,
+    rev: bool,
+}
+
+impl Iterator for FrozenIter {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, entry) = match self.rev {
+            true => self
+                .records
+                .range::<str, _>((Bound::Unbounded, as_ref_bound(&self.next)))
+                .next_back()?,
+            false => self
+                .records
+                .range::<str, _>((as_ref_bound(&self.next), Bound::Unbounded))
+                .next()?,
+        };
+        self.next = Bound::Excluded(key.clone());
+        Some(Ok((key.clone(), entry.clone())))
+    }
+}
+
+impl State {
+    /// Moves the memtable over to the frozen ones, a new one takes the writes from here
+    ///
+    /// An empty memtable is frozen too if the LSN moved, flushing it brings the manifest up to it.
+    fn freeze(&mut self) {
+        let held = self
+            .frozen
+            .first()
+            .map_or(self.persisted_lsn, |frozen| frozen.lsn);
+        if self.memtable.is_empty() && self.lsn == held {
+            return;
+        }
+
+        let records = std::mem::take(&mut self.memtable);
+        self.memtable_size = 0;
+        self.frozen.insert(
+            0,
+            Frozen {
+                records: Arc::new(records),
+                lsn: self.lsn,
+            },
+        );
+    }
+
+    /// all the record streams for a scan, newest first -- they start at the end with rev
+    fn sources(&self, start: &Bound<String>, end: &Bound<String>, rev: bool) -> Vec<RecordIter> {
+        let mut sources: Vec<RecordIter> = Vec::new();
+        let from = if rev { end } else { start };
+
+        let memtable: Vec<io::Result<Record>> = match rev {
+            true => self
+                .memtable
+                .range::<str, _>((Bound::Unbounded, as_ref_bound(end)))
+                .rev()
+                .take_while(|(key, _)| !before_start(key, start))
+                .map(|(key, entry)| Ok((key.clone(), entry.clone())))
+                .collect(),
+            false => self
+                .memtable
+                .range::<str, _>((as_ref_bound(start), Bound::Unbounded))
+                .take_while(|(key, _)| !past_end(key, end))
+                .map(|(key, entry)| Ok((key.clone(), entry.clone())))
+                .collect(),
+        };
+        sources.push(Box::new(memtable.into_iter()));
+
+        for frozen in &self.frozen {
+            sources.push(Box::new(FrozenIter {
+                records: frozen.records.clone(),
+                next: from.clone(),
+                rev,
+            }));
+        }
+
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.iter(from, rev)));
+        }
+
+        for level in &self.levels[1..] {
+            // tables on these levels are disjoint and sorted, so they can just be chained
+            let mut tables: Vec<Arc<SsTable>> = level
+                .iter()
+                .filter(|table| match rev {
+                    true => !past_end(&table.first_key, end),
+                    false => !before_start(&table.last_key, start),
+                })
+                .cloned()
+                .collect();
+            if rev {
+                tables.reverse();
+            }
+            let from = from.clone();
+            sources.push(Box::new(
+                tables
+                    .into_iter()
+                    .flat_map(move |table| table.iter(&from, rev)),
+            ));
+        }
+
+        sources
+    }
+}
+
+fn as_ref_bound(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_str()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_str()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+struct Inner {
+    dir: PathBuf,
+    state: RwLock<State>,
+    next_id: AtomicU64,
+    /// held by whoever is rewriting the levels or the manifest (a flush, a compaction or a
+    /// restore)
+    compaction: Mutex<()>,
+    /// woken up whenever a memtable is frozen
+    wake: (Mutex<bool>, Condvar),
+}
+
+impl Inner {
+    /// hands out the id and path for a new table
+    fn new_table(&self) -> (u64, PathBuf) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        (id, self.dir.join(format!("{:08}.sst", id)))
+    }
+}
+
+/// The LSM tree engine, see the top of this file
+pub(crate) struct LsmEngine {
+    inner: Arc<Inner>,
+}
+
+impl LsmEngine {
+    /// Opens the tables listed in the MANIFEST in dir and starts the compaction thread
+    pub(crate) fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let manifest: Manifest = match fs::read(dir.join("MANIFEST")) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| corrupt(&e.to_string()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut levels: Vec<Vec<Arc<SsTable>>> = vec![Vec::new(); MAX_LEVELS];
+        let mut live = HashSet::new();
+        for (level, ids) in manifest.levels.iter().enumerate().take(MAX_LEVELS) {
+            for id in ids {
+                let path = dir.join(format!("{:08}.sst", id));
+                levels[level].push(Arc::new(SsTable::open(*id, path)?));
+                live.insert(*id);
+            }
+        }
+
+        // tables left behind by a flush or compaction that never made it into the manifest
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".sst"))
+                .and_then(|id| id.parse::<u64>().ok());
+            if let Some(id) = id {
+                if !live.contains(&id) {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        let inner = Arc::new(Inner {
+            dir,
+            state: RwLock::new(State {
+                memtable: BTreeMap::new(),
+                memtable_size: 0,
+                frozen: Vec::new(),
+                levels,
+                lsn: manifest.lsn,
+                persisted_lsn: manifest.lsn,
+            }),
+            next_id: AtomicU64::new(manifest.next_id.max(1)),
+            compaction: Mutex::new(()),
+            wake: (Mutex::new(false), Condvar::new()),
+        });
+
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || compaction_loop(weak));
+
+        Ok(LsmEngine { inner })
+    }
+
+    /// Has the background thread flush what was frozen
+    fn wake(&self) {
+        let (flag, condvar) = &self.inner.wake;
+        *flag.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+
+    fn write(&mut self, key: String, entry: Option<Entry>) -> io::Result<()> {
+        let mut state = self.inner.state.write().unwrap();
+
+        let value_size = entry.as_ref().map_or(0, |entry| {
+            bincode::serialized_size(entry).unwrap_or(0) as usize
+        });
+        state.memtable_size += key.len() + value_size + 16;
+        state.memtable.insert(key, entry);
+        Ok(())
+    }
+}
+
+/// The manifest for the tables in state, saying they hold the WAL up to lsn
+/// The WAL LSN the tables in dir hold, from the MANIFEST and without opening them
+///
+/// None if there is no MANIFEST.
+pub(crate) fn peek_lsn(dir: &Path) -> io::Result<Option<u64>> {
+    match fs::read(dir.join("MANIFEST")) {
+        Ok(data) => {
+            let manifest: Manifest =
+                serde_json::from_slice(&data).map_err(|e| corrupt(&e.to_string()))?;
+            Ok(Some(manifest.lsn))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn manifest(inner: &Inner, state: &State, lsn: u64) -> Manifest {
+    Manifest {
+        next_id: inner.next_id.load(Ordering::SeqCst),
+        levels: state
+            .levels
+            .iter()
+            .map(|level| level.iter().map(|table| table.id).collect())
+            .collect(),
+        lsn,
+    }
+}
+
+/// Only ever called with the compaction mutex held, so that manifests go out in order
+fn write_manifest(inner: &Inner, manifest: &Manifest) -> io::Result<()> {
+    let dir = &inner.dir;
+
+    // write to the side and rename, so a crash never leaves half a manifest behind
+    let tmp = dir.join("MANIFEST.tmp");
+    let data = serde_json::to_vec(manifest).map_err(|e| corrupt(&e.to_string()))?;
+    let mut file = File::create(&tmp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(tmp, dir.join("MANIFEST"))?;
+    // the rename only sticks once the directory is synced -- the WAL the manifest covers may be
+    // deleted right after this, an older manifest coming back would need it
+    File::open(dir)?.sync_all()
+}
+
+/// Writes the oldest frozen memtable out as an L0 table and moves the manifest up to its LSN
+///
+/// returns false if nothing was frozen. The state lock is only held to swap the table in, the
+/// writing happens beside it.
+fn flush_frozen(inner: &Inner) -> io::Result<bool> {
+    let _compaction = inner.compaction.lock().unwrap();
+    let (records, lsn) = match inner.state.read().unwrap().frozen.last() {
+        Some(frozen) => (frozen.records.clone(), frozen.lsn),
+        None => return Ok(false),
+    };
+
+    let table = if records.is_empty() {
+        None
+    } else {
+        let (id, path) = inner.new_table();
+        let mut writer = TableWriter::create(path)?;
+        for (key, entry) in records.iter() {
+            writer.add(key, entry)?;
+        }
+        Some(Arc::new(SsTable::open(id, writer.finish()?)?))
+    };
+
+    let manifest = {
+        let mut state = inner.state.write().unwrap();
+        if let Some(table) = table {
+            state.levels[0].insert(0, table);
+        }
+        state.frozen.pop();
+        manifest(inner, &state, lsn)
+    };
+    // until the manifest is out, a restart goes back to the one before -- the WAL after its LSN
+    // is still there, so nothing is lost
+    write_manifest(inner, &manifest)?;
+    inner.state.write().unwrap().persisted_lsn = lsn;
+    Ok(true)
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, key: &str) -> io::Result<Option<Entry>> {
+        let state = self.inner.state.read().unwrap();
+
+        if let Some(entry) = state.memtable.get(key) {
+            return Ok(entry.clone());
+        }
+        for frozen in &state.frozen {
+            if let Some(entry) = frozen.records.get(key) {
+                return Ok(entry.clone());
+            }
+        }
+
+        // L0 tables newest first, then one table per level below
+        for table in &state.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(entry);
+            }
+        }
+        for level in &state.levels[1..] {
+            let i = level.partition_point(|table| table.last_key.as_str() < key);
+            if let Some(table) = level.get(i) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(entry);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn put(&mut self, key: String, entry: Entry) -> io::Result<()> {
+        self.write(key, Some(entry))
+    }
+
+    // a tombstone whether the key is there or not, finding out would take a read
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.write(key.to_string(), None)
+    }
+
+    fn range(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        rev: bool,
+        visit: &mut dyn FnMut(&str, &Entry) -> bool,
+    ) -> io::Result<()> {
+        let (start, end) = (owned_bound(start), owned_bound(end));
+        let sources = self.inner.state.read().unwrap().sources(&start, &end, rev);
+        let stop = if rev { start } else { end };
+        let live = MergeIter::new(sources, stop, rev).filter_map(|record| match record {
+            Ok((key, Some(entry))) => Some(Ok((key, entry))),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        });
+
+        for item in live {
+            let (key, entry) = item?;
+            if !visit(&key, &entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<Snapshot> {
+        // the tables and frozen memtables are immutable and the memtable is copied, so this view
+        // never changes
+        let sources =
+            self.inner
+                .state
+                .read()
+                .unwrap()
+                .sources(&Bound::Unbounded, &Bound::Unbounded, false);
+
+        Ok(Box::new(
+            MergeIter::new(sources, Bound::Unbounded, false).filter_map(|record| match record {
+                Ok((key, Some(entry))) => Some(Ok((key, entry))),
+                Ok((_, None)) => None,
+                Err(e) => Some(Err(e)),
+            }),
+        ))
+    }
+
+    fn restore(&mut self, entries: &mut dyn Iterator<Item = (String, Entry)>) -> io::Result<()> {
+        let _compaction = self.inner.compaction.lock().unwrap();
+        let mut state = self.inner.state.write().unwrap();
+
+        let old: Vec<Arc<SsTable>> = state.levels.iter().flatten().cloned().collect();
+        state.memtable.clear();
+        state.memtable_size = 0;
+        state.frozen.clear();
+        state.lsn = 0;
+        state.persisted_lsn = 0;
+        for level in state.levels.iter_mut() {
+            level.clear();
+        }
+
+        // the entries come sorted, so they can go straight to the bottom level
+        let mut records = entries.map(|(key, entry)| Ok((key, Some(entry))));
+        state.levels[MAX_LEVELS - 1] = write_tables(&self.inner, &mut records, true)?;
+        write_manifest(&self.inner, &manifest(&self.inner, &state, 0))?;
+
+        for table in old {
+            let _ = fs::remove_file(&table.path);
+        }
+        Ok(())
+    }
+
+    fn set_lsn(&mut self, lsn: u64) -> io::Result<()> {
+        let mut state = self.inner.state.write().unwrap();
+        state.lsn = lsn;
+
+        if state.memtable_size >= MEMTABLE_LIMIT {
+            state.freeze();
+            let behind = state.frozen.len() > MAX_FROZEN;
+            drop(state);
+
+            self.wake();
+            if behind {
+                flush_frozen(&self.inner)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn persisted_lsn(&self) -> Option<u64> {
+        Some(self.inner.state.read().unwrap().persisted_lsn)
+    }
+
+    fn checkpoint(&self) -> io::Result<Checkpoint> {
+        let mut state = self.inner.state.write().unwrap();
+
+        // the WAL still has what is in the memtable, so a small one is not worth a table of its
+        // own -- an empty one only needs its LSN in the manifest
+        if state.memtable.is_empty()
+            || state.memtable_size >= CHECKPOINT_MIN_SIZE
+            || state.lsn.saturating_sub(state.persisted_lsn) >= CHECKPOINT_MAX_RECORDS
+        {
+            state.freeze();
+        }
+
+        let inner = self.inner.clone();
+        Ok(Box::new(move || {
+            while flush_frozen(&inner)? {}
+            Ok(())
+        }))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        // unlike a checkpoint, nothing is left to the WAL
+        self.inner.state.write().unwrap().freeze();
+        while flush_frozen(&self.inner)? {}
+        Ok(())
+    }
+}
+
+/// Writes sorted records into as many TABLE_SIZE tables as needed
+///
+/// Tombstones are dropped when `bottom` is set, there is nothing below for them to hide.
+fn write_tables(
+    inner: &Inner,
+    records: &mut dyn Iterator<Item = io::Result<Record>>,
+    bottom: bool,
+) -> io::Result<Vec<Arc<SsTable>>> {
+    let mut tables = Vec::new();
+    let mut writer: Option<(u64, TableWriter)> = None;
+
+    for record in records {
+        let (key, entry) = record?;
+        if entry.is_none() && bottom {
+            continue;
+        }
+
+        if writer.is_none() {
+            let (id, path) = inner.new_table();
+            writer = Some((id, TableWriter::create(path)?));
+        }
+
+        let (id, table) = writer.as_mut().unwrap();
+        table.add(&key, &entry)?;
+        if table.size() >= TABLE_SIZE {
+            let (id, table) = (*id, writer.take().unwrap().1);
+            tables.push(Arc::new(SsTable::open(id, table.finish()?)?));
+        }
+    }
+
+    if let Some((id, table)) = writer {
+        if !table.is_empty() {
+            tables.push(Arc::new(SsTable::open(id, table.finish()?)?));
+        }
+    }
+    Ok(tables)
+}
+
+fn level_size(level: &[Arc<SsTable>]) -> u64 {
+    level.iter().map(|table| table.size).sum()
+}
+
+fn level_max_bytes(level: usize) -> u64 {
+    L1_MAX_BYTES * LEVEL_MULTIPLIER.pow(level as u32 - 1)
+}
+
+fn compaction_loop(inner: Weak<Inner>) {
+    loop {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return, // the engine is gone
+        };
+
+        loop {
+            match flush_frozen(&inner) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("LSM flush failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        // keep going while there is something to compact
+        loop {
+            match compact_once(&inner) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("LSM compaction failed: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let (flag, condvar) = &inner.wake;
+        let mut woken = flag.lock().unwrap();
+        if !*woken {
+            woken = condvar
+                .wait_timeout(woken, Duration::from_secs(10))
+                .unwrap()
+                .0;
+        }
+        *woken = false;
+    }
+}
+
+/// Runs one compaction if a level is over its limit, returns whether it did anything
+fn compact_once(inner: &Inner) -> io::Result<bool> {
+    let _compaction = inner.compaction.lock().unwrap();
+
+    // pick the inputs: all of L0 once it has too many tables, otherwise the first table of the
+    // first level that grew too big, together with what it overlaps one level down
+    let (level, upper, lower) = {
+        let state = inner.state.read().unwrap();
+
+        let picked = if state.levels[0].len() >= L0_COMPACTION_TRIGGER {
+            Some((0, state.levels[0].clone()))
+        } else {
+            (1..MAX_LEVELS - 1)
+                .find(|&level| level_size(&state.levels[level]) > level_max_bytes(level))
+                .map(|level| (level, vec![state.levels[level][0].clone()]))
+        };
+
+        let (level, upper) = match picked {
+            Some(picked) => picked,
+            None => return Ok(false),
+        };
+
+        let first = upper.iter().map(|t| t.first_key.clone()).min().unwrap();
+        let last = upper.iter().map(|t| t.last_key.clone()).max().unwrap();
+        let lower: Vec<Arc<SsTable>> = state.levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(&first, &last))
+            .cloned()
+            .collect();
+
+        (level, upper, lower)
+    };
+
+    let target = level + 1;
+    let bottom = {
+        let state = inner.state.read().unwrap();
+        state.levels[target + 1..]
+            .iter()
+            .all(|level| level.is_empty())
+    };
+
+    // newer tables first -- upper before lower, and L0 is already kept newest first
+    let mut sources: Vec<RecordIter> = upper
+        .iter()
+        .map(|table| Box::new(table.iter(&Bound::Unbounded, false)) as RecordIter)
+        .collect();
+    let lower_tables = lower.clone();
+    sources.push(Box::new(
+        lower_tables
+            .into_iter()
+            .flat_map(|table| table.iter(&Bound::Unbounded, false)),
+    ));
+    let mut merged = MergeIter::new(sources, Bound::Unbounded, false);
+
+    // the merge runs without the state lock, readers and writers carry on meanwhile
+    let output = write_tables(inner, &mut merged, bottom)?;
+
+    // swap the inputs for the output
+    let inputs: HashSet<u64> = upper.iter().chain(&lower).map(|t| t.id).collect();
+    let manifest = {
+        let mut state = inner.state.write().unwrap();
+        state.levels[level].retain(|table| !inputs.contains(&table.id));
+        state.levels[target].retain(|table| !inputs.contains(&table.id));
+        state.levels[target].extend(output);
+        state.levels[target].sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        manifest(inner, &state, state.persisted_lsn)
+    };
+    // the inputs stay on disk until the manifest no longer lists them
+    write_manifest(inner, &manifest)?;
+
+    for table in upper.iter().chain(&lower) {
+        let _ = fs::remove_file(&table.path);
+    }
+
+    eprintln!(
+        "LSM compaction: {} tables of L{} merged into L{}",
+        inputs.len(),
+        level,
+        target
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rocs-lsm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// xorshift, the tests only need something that looks random and can be replayed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn entry(n: u64) -> Entry {
+        Entry {
+            value: Value::Str(format!("value{}", n).repeat(n as usize % 20 + 1)),
+            expires_at: None,
+        }
+    }
+
+    /// one WAL record's worth of change
+    fn random_change(
+        rng: &mut Rng,
+        engine: &mut LsmEngine,
+        model: &mut BTreeMap<String, Entry>,
+        lsn: &mut u64,
+    ) {
+        let key = format!("key{:05}", rng.below(2000));
+
+        if rng.below(3) == 0 {
+            engine.delete(&key).unwrap();
+            model.remove(&key);
+        } else {
+            let entry = entry(rng.below(1000));
+            engine.put(key.clone(), entry.clone()).unwrap();
+            model.insert(key, entry);
+        }
+        *lsn += 1;
+        engine.set_lsn(*lsn).unwrap();
+    }
+
+    fn assert_same(rng: &mut Rng, engine: &LsmEngine, model: &BTreeMap<String, Entry>) {
+        let entries = engine
+            .snapshot()
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let expected: Vec<_> = model.iter().map(|(k, e)| (k.clone(), e.clone())).collect();
+        assert_eq!(entries, expected);
+
+        for _ in 0..100 {
+            let key = format!("key{:05}", rng.below(2000));
+            assert_eq!(engine.get(&key).unwrap().as_ref(), model.get(&key));
+        }
+
+        // both ways, with either kind of bound, stopping early like a LIMIT does
+        for rev in [false, true] {
+            let start = format!("key{:05}", rng.below(2000));
+            let end = format!("key{:05}", rng.below(2000));
+            let bounds = match rng.below(2) {
+                0 => (Bound::Included(start.clone()), Bound::Excluded(end.clone())),
+                _ => (Bound::Excluded(start.clone()), Bound::Included(end.clone())),
+            };
+            let limit = 1 + rng.below(300) as usize;
+
+            let mut found = Vec::new();
+            engine
+                .range(
+                    as_ref_bound(&bounds.0),
+                    as_ref_bound(&bounds.1),
+                    rev,
+                    &mut |key, entry| {
+                        found.push((key.to_string(), entry.clone()));
+                        found.len() < limit
+                    },
+                )
+                .unwrap();
+            let mut expected: Vec<_> = if start < end {
+                model
+                    .range(bounds.clone())
+                    .map(|(k, e)| (k.clone(), e.clone()))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            if rev {
+                expected.reverse();
+            }
+            expected.truncate(limit);
+            assert_eq!(found, expected, "range {:?} rev {}", bounds, rev);
+        }
+    }
+
+    /// writes the memtable out as a table, like a full one is
+    fn flush(engine: &LsmEngine) {
+        engine.inner.state.write().unwrap().freeze();
+        while flush_frozen(&engine.inner).unwrap() {}
+    }
+
+    /// runs every compaction there is to run, so that none is left for the background thread
+    fn compact_all(engine: &LsmEngine) {
+        while compact_once(&engine.inner).unwrap() {}
+    }
+
+    #[test]
+    fn matches_a_btreemap_across_reopens_and_compactions() {
+        let dir = test_dir("model");
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut model = BTreeMap::new();
+        let mut lsn = 0;
+        let mut engine = LsmEngine::open(&dir).unwrap();
+        // what the tables hold, which is all that is left after a restart
+        let mut flushed = (model.clone(), lsn);
+
+        for round in 0..24 {
+            for _ in 0..400 {
+                random_change(&mut rng, &mut engine, &mut model, &mut lsn);
+            }
+            assert_same(&mut rng, &engine, &model);
+
+            if round % 2 == 0 {
+                flush(&engine);
+                flushed = (model.clone(), lsn);
+            }
+            if round % 5 == 4 {
+                compact_all(&engine);
+                assert_same(&mut rng, &engine, &model);
+            }
+
+            if round % 3 == 2 {
+                // the memtable is lost, the WAL would replay it
+                compact_all(&engine);
+                drop(engine);
+                engine = LsmEngine::open(&dir).unwrap();
+                (model, lsn) = flushed.clone();
+                assert_eq!(engine.persisted_lsn(), Some(lsn));
+                assert_same(&mut rng, &engine, &model);
+            }
+        }
+
+        let state = engine.inner.state.read().unwrap();
+        assert!(state.levels[1..].iter().any(|level| !level.is_empty()));
+        drop(state);
+        compact_all(&engine);
+        drop(engine);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frozen_memtables_are_read_until_a_checkpoint_flushes_them() {
+        let dir = test_dir("frozen");
+        let mut rng = Rng(0x1234_5678_9abc_def1);
+        let mut model = BTreeMap::new();
+        let mut lsn = 0;
+        let mut engine = LsmEngine::open(&dir).unwrap();
+
+        for _ in 0..3 {
+            for _ in 0..300 {
+                random_change(&mut rng, &mut engine, &mut model, &mut lsn);
+            }
+            engine.inner.state.write().unwrap().freeze();
+        }
+        let frozen = (model.clone(), lsn);
+        // newer writes on top of the frozen ones
+        for _ in 0..300 {
+            random_change(&mut rng, &mut engine, &mut model, &mut lsn);
+        }
+        assert_same(&mut rng, &engine, &model);
+
+        // the memtable is small and stays, the frozen ones make it to disk
+        engine.checkpoint().unwrap()().unwrap();
+        let state = engine.inner.state.read().unwrap();
+        assert!(state.frozen.is_empty());
+        assert!(!state.memtable.is_empty());
+        assert_eq!(state.persisted_lsn, frozen.1);
+        drop(state);
+        assert_same(&mut rng, &engine, &model);
+
+        drop(engine);
+        let engine = LsmEngine::open(&dir).unwrap();
+        assert_eq!(engine.persisted_lsn(), Some(frozen.1));
+        assert_same(&mut rng, &engine, &frozen.0);
+
+        drop(engine);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_checkpoint_leaves_a_small_memtable_to_the_wal() {
+        let dir = test_dir("checkpoint");
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut model = BTreeMap::new();
+        let mut lsn = 0;
+        let mut engine = LsmEngine::open(&dir).unwrap();
+
+        for _ in 0..100 {
+            random_change(&mut rng, &mut engine, &mut model, &mut lsn);
+        }
+        engine.checkpoint().unwrap()().unwrap();
+        let state = engine.inner.state.read().unwrap();
+        assert!(state.levels.iter().all(|level| level.is_empty()));
+        assert_eq!(state.persisted_lsn, 0);
+        drop(state);
+
+        // a sync takes it all, after that only the LSN has to go to the manifest
+        engine.sync().unwrap();
+        assert_eq!(engine.persisted_lsn(), Some(lsn));
+        lsn += 5;
+        engine.set_lsn(lsn).unwrap();
+        engine.checkpoint().unwrap()().unwrap();
+        assert_eq!(engine.inner.state.read().unwrap().levels[0].len(), 1);
+        drop(engine);
+
+        let engine = LsmEngine::open(&dir).unwrap();
+        assert_eq!(engine.persisted_lsn(), Some(lsn));
+        assert_same(&mut rng, &engine, &model);
+
+        drop(engine);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
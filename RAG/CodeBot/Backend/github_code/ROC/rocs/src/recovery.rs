@@ -1,52 +1,300 @@
 use crate::command::Command;
 use crate::logger;
+use crate::snapshot;
 use crate::store;
-use std::io;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
 
-pub fn handle_recovery() -> io::Result<()> {
+// Startup protocol: while rocs runs, logs/rocs.lock holds its PID and an exclusive lock. A clean
+// SHUTDOWN empties the file, so finding a PID in it at startup means the last run was killed or
+// lost power. The lock itself keeps a second server off the same files.
+const LOCK_PATH: &str = "logs/rocs.lock";
+
+/// the lock file, held open for as long as the server runs
+static LOCK: Mutex<Option<File>> = Mutex::new(None);
+
+/// Takes the lock file and marks the run as in progress
+///
+/// returns whether the previous run went down without a clean shutdown
+pub(crate) fn acquire_lock() -> io::Result<bool> {
+    fs::create_dir_all("logs")?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(LOCK_PATH)?;
+
+    if file.try_lock().is_err() {
+        return Err(io::Error::other(format!(
+            "{} is locked, is another rocs running?",
+            LOCK_PATH
+        )));
+    }
+
+    let mut previous = String::new();
+    file.read_to_string(&mut previous)?;
+    let crashed = !previous.trim().is_empty();
+    if crashed {
+        eprintln!(
+            "rocs (pid {}) did not shut down cleanly last time",
+            previous.trim()
+        );
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", std::process::id())?;
+    file.sync_all()?;
+
+    *LOCK.lock().unwrap() = Some(file);
+    Ok(crashed)
+}
+
+/// Marks the run as cleanly finished, call it right before exiting
+pub(crate) fn release_lock() -> io::Result<()> {
+    if let Some(file) = LOCK.lock().unwrap().take() {
+        file.set_len(0)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Loads the latest snapshot and replays every WAL record it does not include yet
+///
+/// The snapshot knows the last LSN it holds, so the replay is exact whether or not the last
+/// run crashed -- `crashed` only decides how loud we are about it. A disk engine that holds an
+/// LSN of its own skips the snapshot, see `store::load_store`.
+pub fn handle_recovery(crashed: bool) -> io::Result<()> {
     eprintln!("inside recovery module!");
 
-    if let Err(e) = store::load_store("../snaps/snapshots.json") {
-        eprintln!("Failed to load snapshot: {}", e);
-    } else {
-        eprintln!("Successfully loaded the snapshot");
-    }
-
-    let last_checkpoint = logger::get_health_checkpoint();
-
-    match last_checkpoint {
-        Some(status) => {
-            if status == "CLEAN" {
-                eprintln!("No recovery needed!");
-            } else {
-                eprintln!("DIRTY! There was a crash previously! \n Starting Recovery!");
-
-                let wal_entries = logger::read_wal()?;
-
-                for cmd in wal_entries {
-                    match cmd {
-                        Command::Store { key, value } => {
-                            store::store_values(key, value);
-                        }
-                        Command::Delete { key } => {
-                            let _ = store::delete_val(key);
-                        }
-                        Command::Update { key, value } => {
-                            store::update_val(key, value);
-                        }
-                        _ => {
-                            // pass -- non modifying command
-                        }
-                    }
-                }
-
-                eprintln!("State recovery complete. Exiting recovery mode");
+    // snapshots that exist but can not be read stop the startup, coming up empty would lose them
+    let loaded_lsn = store::load_store(snapshot::SNAPSHOT_DIR)?;
+    eprintln!("Successfully loaded the store up to LSN {}", loaded_lsn);
+
+    // always read the WAL -- it repairs a torn tail and tells the logger where the LSNs are at
+    let wal_entries = logger::read_wal()?;
+    // even with the WAL gone, new records have to come after what is loaded
+    logger::advance_lsn(loaded_lsn + 1);
+
+    let missing: Vec<_> = wal_entries
+        .into_iter()
+        .filter(|record| record.lsn > loaded_lsn)
+        .collect();
+
+    if let Some(first) = missing.first() {
+        if first.lsn != loaded_lsn + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the store ends at LSN {} but the WAL only goes back to LSN {}",
+                    loaded_lsn, first.lsn
+                ),
+            ));
+        }
+    }
+
+    if crashed {
+        eprintln!("DIRTY! There was a crash previously! \n Starting Recovery!");
+    }
+    if missing.is_empty() {
+        eprintln!("No recovery needed!");
+        store::set_applied_lsn(logger::last_lsn())?;
+        return Ok(());
+    }
+
+    let mut last_lsn = loaded_lsn;
+    for record in missing {
+        last_lsn = record.lsn;
+        apply(record.command, record.lsn)?;
+    }
+    store::set_applied_lsn(logger::last_lsn())?;
+
+    eprintln!(
+        "Replayed the WAL from LSN {} to {}. Exiting recovery mode",
+        loaded_lsn + 1,
+        last_lsn
+    );
+    Ok(())
+}
+
+/// Applies a command read back from the WAL to the store
+///
+/// A command that was refused the first time was never logged, and with `now` at 0 every key
+/// still counts as alive -- so a record refused now means the replay went somewhere the live run
+/// did not. It is skipped, and said so with its LSN so that it can be looked at with roc-tool.
+/// The same goes for a record the engine turns down (older versions logged writes before
+/// checking them against the engine), rather than failing every start from here on.
+fn apply(mut command: Command, lsn: u64) -> io::Result<()> {
+    let changes = match store::plan(&mut command, 0, &mut |key| store::get_entry(key))? {
+        Ok(changes) => changes,
+        Err(msg) => {
+            eprintln!(
+                "WAL: record at LSN {} refused on replay, skipping it: {} ({:?})",
+                lsn, msg, command
+            );
+            Vec::new()
+        }
+    };
+    match store::apply_changes(changes, lsn) {
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            eprintln!(
+                "WAL: record at LSN {} can not be applied, skipping it: {} ({:?})",
+                lsn, e, command
+            );
+            store::set_applied_lsn(lsn)
+        }
+        result => result,
+    }
+}
+
+/// How far a point in time recovery goes
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RecoveryTarget {
+    /// up to and including this LSN
+    Lsn(u64),
+    /// up to and including records written at this unix time in milliseconds
+    Time(u64),
+}
+
+/// What `rocs recover ...` asked for
+#[derive(Debug)]
+pub(crate) struct PitrOptions {
+    pub(crate) target: RecoveryTarget,
+    /// the last LSN of the snapshot to start from, the newest one before the target otherwise
+    pub(crate) snapshot: Option<u64>,
+    /// write the recovered snapshot and exit instead of starting the server
+    pub(crate) exit: bool,
+}
+
+pub(crate) const PITR_USAGE: &str =
+    "usage: rocs recover (--lsn <lsn> | --time <unix seconds>) [--snapshot <lsn>] [--exit]";
+
+/// Parses the arguments after `rocs recover`
+pub(crate) fn parse_pitr_args(args: &[String]) -> Result<PitrOptions, String> {
+    let mut target = None;
+    let mut snapshot = None;
+    let mut exit = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.clone().next().map(String::as_str);
+        let lsn = || {
+            value
+                .and_then(|n| n.parse::<u64>().ok())
+                .ok_or_else(|| format!("{} needs an LSN", arg))
+        };
+
+        match arg.as_str() {
+            "--lsn" => target = Some(RecoveryTarget::Lsn(lsn()?)),
+            // fractions of a second are fine, the WAL keeps milliseconds
+            "--time" => {
+                let secs = value
+                    .and_then(|n| n.parse::<f64>().ok())
+                    .filter(|n| n.is_finite() && *n >= 0.0)
+                    .ok_or_else(|| format!("{} needs a unix time in seconds", arg))?;
+                target = Some(RecoveryTarget::Time((secs * 1000.0) as u64));
             }
+            "--snapshot" => snapshot = Some(lsn()?),
+            "--exit" => {
+                exit = true;
+                continue;
+            }
+            other => return Err(format!("unknown argument {:?}", other)),
         }
-        _ => {
-            eprintln!("Could not get status");
-            return Err(io::Error::new(io::ErrorKind::Other, "Could not get status"));
+        // skip the value
+        args.next();
+    }
+
+    Ok(PitrOptions {
+        target: target.ok_or_else(|| "either --lsn or --time is required".to_string())?,
+        snapshot,
+        exit,
+    })
+}
+
+/// Point in time recovery: restores a snapshot and replays the WAL up to the target
+///
+/// Everything past the target -- the WAL segments and the snapshots that include later writes --
+/// is moved to logs/pitr-<time>/ rather than deleted, and a fresh snapshot of the recovered
+/// state is saved, so the server carries on from there.
+pub(crate) fn point_in_time(options: &PitrOptions) -> io::Result<()> {
+    eprintln!("Point in time recovery to {:?}", options.target);
+
+    let dir = Path::new(snapshot::SNAPSHOT_DIR);
+    // a snapshot written after the target time can already hold later writes
+    let before_target = |header: &snapshot::SnapshotHeader| match options.target {
+        RecoveryTarget::Lsn(lsn) => header.last_lsn <= lsn,
+        RecoveryTarget::Time(time) => header.created_at <= time,
+    };
+    let load: snapshot::Load = &mut |_, entries| store::restore_entries(entries);
+    let header = match options.snapshot {
+        Some(lsn) => snapshot::read_matching(
+            dir,
+            &|header| header.last_lsn == lsn && before_target(header),
+            load,
+        )?
+        .ok_or_else(|| {
+            io::Error::other(format!(
+                "no readable snapshot at LSN {} that is not past the target",
+                lsn
+            ))
+        })?,
+        None => match snapshot::read_matching(dir, &before_target, load)? {
+            Some(header) => header,
+            None => {
+                store::restore_entries(&mut std::iter::empty())?;
+                snapshot::SnapshotHeader::default()
+            }
+        },
+    };
+    store::finish_restore(header.last_lsn)?;
+    eprintln!("Restored the snapshot up to LSN {}", header.last_lsn);
+
+    let records = logger::read_wal()?;
+    logger::advance_lsn(header.last_lsn + 1);
+
+    let mut last_lsn = header.last_lsn;
+    for record in records.into_iter().filter(|r| r.lsn > header.last_lsn) {
+        let within = match options.target {
+            RecoveryTarget::Lsn(lsn) => record.lsn <= lsn,
+            RecoveryTarget::Time(time) => record.timestamp <= time,
+        };
+        if !within {
+            break;
+        }
+        if record.lsn != last_lsn + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the WAL jumps from LSN {} to LSN {}, the records between are gone",
+                    last_lsn, record.lsn
+                ),
+            ));
         }
+
+        last_lsn = record.lsn;
+        apply(record.command, record.lsn)?;
     }
+    eprintln!("Replayed the WAL up to LSN {}", last_lsn);
+    // a disk engine carries on from what it has on disk after a restart, and the WAL it would
+    // replay on top of that is about to go
+    store::sync_engine()?;
+
+    // keep the undone history around, out of the way of the next recovery
+    let archive = Path::new("logs").join(format!("pitr-{}", store::now_millis()));
+    let segments = logger::archive_segments(&archive)?;
+    let snapshots = snapshot::archive_snapshots_after(dir, last_lsn, &archive)?;
+    eprintln!(
+        "Moved {} WAL segments and {} snapshots to {:?}",
+        segments, snapshots, archive
+    );
+
+    // the new snapshot takes the highest LSN ever handed out, so that new writes keep counting
+    // up from there
+    store::set_applied_lsn(logger::last_lsn())?;
+    store::save_store(dir)?;
     Ok(())
 }
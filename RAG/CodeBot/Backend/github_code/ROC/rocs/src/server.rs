@@ -0,0 +1,602 @@
+// ROC/rocs/src/server.rs
+
+use crate::command::{Command, Condition};
+use crate::{codec, config, engine, expiry, logger, recovery, snapshot, store, value, writer};
+use serde_json::{self, json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+/// Runs the rocs server, this is all of `main` for the rocs binary
+pub fn run() -> io::Result<()> {
+    // `rocs recover ...` rolls the store back to a point in time before serving
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let pitr = match args.first().map(String::as_str) {
+        None => None,
+        Some("recover") => match recovery::parse_pitr_args(&args[1..]) {
+            Ok(options) => Some(options),
+            Err(msg) => {
+                eprintln!("{}\n{}", msg, recovery::PITR_USAGE);
+                std::process::exit(2);
+            }
+        },
+        Some(other) => {
+            eprintln!("unknown argument {:?}\n{}", other, recovery::PITR_USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // keep a second server off our files, and find out how the last run ended
+    let crashed = recovery::acquire_lock()?;
+
+    // a bad key file should stop the startup, not the first write
+    codec::cipher()?;
+
+    // pick the storage engine before anything touches the store
+    let engine = engine::open(&config::CONFIG)?;
+    store::set_engine(engine)?;
+
+    // handle the recovery -- starting on top of a damaged log would lose writes for good
+    let recovered = match &pitr {
+        Some(options) => recovery::point_in_time(options),
+        None => recovery::handle_recovery(crashed),
+    };
+    if let Err(e) = recovered {
+        eprintln!("Recovery failed: {}", e);
+        std::process::exit(1);
+    }
+
+    if pitr.is_some_and(|options| options.exit) {
+        recovery::release_lock()?;
+        eprintln!("Point in time recovery done, exiting");
+        return Ok(());
+    }
+
+    // snapshots on a timer, after so many writes or so much WAL -- see CONFIG
+    snapshot::take_snapshots(snapshot::SNAPSHOT_DIR, config::CONFIG.snapshot_policy);
+
+    // drop expired keys in the background every second
+    expiry::sweep_expired(1);
+
+    // with fsync=everysec the WAL gets synced in the background
+    logger::start_fsync_thread();
+
+    // let's make an admin thread to control the server
+    thread::spawn(handle_admin);
+
+    let listener = TcpListener::bind(&config::CONFIG.addr)?;
+
+    // to handle the clients connected on the port
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                eprintln!("received connection : {:#?}", stream);
+
+                thread::spawn(move || handle_client(stream));
+            }
+            Err(e) => {
+                eprintln!("Encountered error while receiving connection: {:#?}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_client(mut stream: TcpStream) {
+    let reader_stream = stream
+        .try_clone()
+        .expect("failed to clone the stream for the reader -- func handle_client");
+    let mut reader = BufReader::new(reader_stream);
+    let mut line = String::new();
+    let mut session = Session::default();
+
+    // let's setup to continuously read commands from the client
+    while let Ok(bytes_read) = reader.read_line(&mut line)
+    // this one reads into the above line variable
+    {
+        if bytes_read == 0 {
+            break; // connection closed
+        }
+
+        let command_str = line.trim(); // eg. STORE ashu 12  -- something like this for now
+
+        if !command_str.is_empty() {
+            let request: Value = match serde_json::from_str::<Value>(command_str) {
+                Ok(req) => req,
+                Err(_) => {
+                    eprintln!("Invalid json received");
+                    // Command::ERR {msg: "Invalid json received".to_string()}
+                    let error_response = json!({"error": "Invalid JSON received!"}).to_string();
+                    stream.write_all(error_response.as_bytes()).ok();
+                    line.clear();
+                    continue;
+                }
+            };
+
+            let command = handle_transaction(&request, &mut session)
+                .unwrap_or_else(|| handle_request(&request))
+                .unwrap_or_else(|msg| Command::ERR { msg });
+
+            let response = serde_json::to_string(&command)
+                .unwrap_or_else(|_| "{\"error\": \"Failed to serialize response\"}".to_string());
+
+            let response_str = response + "\n";
+            stream
+                .write_all(response_str.as_bytes())
+                .expect("Failed to send a response!");
+        }
+
+        line.clear();
+    }
+}
+
+/// What a connection keeps between requests
+#[derive(Default)]
+struct Session {
+    /// set between MULTI and EXEC or DISCARD
+    transaction: Option<Transaction>,
+    /// keys from WATCH with their version at the time, EXEC fails if any of them changed since
+    watched: Vec<(String, u64)>,
+}
+
+impl Session {
+    /// Lets go of the watched keys, the store only tracks keys as long as someone watches them
+    fn unwatch(&mut self) {
+        store::unwatch(&std::mem::take(&mut self.watched));
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+/// The writes queued on a connection since MULTI
+#[derive(Default)]
+struct Transaction {
+    queued: Vec<Command>,
+    /// a request could not be queued, so EXEC refuses the whole transaction
+    failed: bool,
+}
+
+/// Handles MULTI, EXEC, DISCARD, WATCH and UNWATCH, and queues the requests in between
+///
+/// returns None if the request is not part of a transaction and runs right away
+fn handle_transaction(request: &Value, session: &mut Session) -> Option<Result<Command, String>> {
+    let reply = match (request["command"].as_str(), session.transaction.as_mut()) {
+        (Some("MULTI"), None) => {
+            session.transaction = Some(Transaction::default());
+            Ok(Command::Multi)
+        }
+        (Some("MULTI"), Some(_)) => Err("MULTI calls can not be nested".to_string()),
+        (Some("EXEC"), Some(_)) => {
+            let Transaction { queued, failed } = session.transaction.take()?;
+            // EXEC is the end of the watches, whatever happens to the transaction
+            // (only after the writer checked them, they must stay tracked until then)
+            let watched = std::mem::take(&mut session.watched);
+            let reply = if failed {
+                Err("Transaction discarded because of earlier errors".to_string())
+            } else if queued.is_empty() {
+                // nothing to log, the watches can be checked right here
+                exec_nothing(&watched)
+            } else {
+                // the whole transaction is one WAL record, applied under one store lock
+                writer::submit_watched(Command::Exec { commands: queued }, watched.clone())
+            };
+            store::unwatch(&watched);
+            reply
+        }
+        (Some("DISCARD"), Some(_)) => {
+            session.transaction = None;
+            session.unwatch();
+            Ok(Command::Discard)
+        }
+        (Some(name @ ("EXEC" | "DISCARD")), None) => Err(format!("{} without MULTI", name)),
+        (Some("WATCH"), None) => watch(request, &mut session.watched),
+        (Some("UNWATCH"), None) => {
+            session.unwatch();
+            Ok(Command::Unwatch)
+        }
+        (Some(name @ ("WATCH" | "UNWATCH")), Some(_)) => {
+            Err(format!("{} inside MULTI is not allowed", name))
+        }
+        (_, None) => return None,
+        (_, Some(transaction)) => match parse_write(request) {
+            Ok(Some(command)) => {
+                transaction.queued.push(command);
+                Ok(Command::Queued)
+            }
+            Ok(None) => {
+                transaction.failed = true;
+                Err("Only writes can be queued in a transaction".to_string())
+            }
+            Err(msg) => {
+                transaction.failed = true;
+                Err(msg)
+            }
+        },
+    };
+    Some(reply)
+}
+
+/// Remembers the current version of the requested keys
+fn watch(request: &Value, watched: &mut Vec<(String, u64)>) -> Result<Command, String> {
+    let keys = keys_field(request, "WATCH")?;
+
+    for key in &keys {
+        watched.push((key.clone(), store::watch(key)));
+    }
+    Ok(Command::Watch { keys })
+}
+
+/// EXEC of an empty transaction, which still fails if a watched key changed
+fn exec_nothing(watched: &[(String, u64)]) -> Result<Command, String> {
+    for (key, version) in watched {
+        if store::version(key) != Some(*version) {
+            return Err("Transaction aborted: a watched key changed".to_string());
+        }
+    }
+    Ok(Command::Exec {
+        commands: Vec::new(),
+    })
+}
+
+/// Executes a single request, an Err is sent back to the client as Command::ERR
+fn handle_request(request: &Value) -> Result<Command, String> {
+    // reads are answered right here, writes only count once they are in the WAL
+    if let Some(command) = parse_write(request)? {
+        return writer::submit(command);
+    }
+
+    let command = match request["command"].as_str() {
+        Some("PING") => Command::Ping,
+        Some("FETCH") => {
+            let key = key_field(request)?;
+
+            match store::fetch_values(key.to_string()).map_err(storage_error)? {
+                Some(val) => Command::Fetch {
+                    key: key.to_string(),
+                    value: Some(val),
+                },
+                None => return Err("Value not found in storage!".to_string()),
+            }
+        }
+        Some("MGET") => {
+            let keys = keys_field(request, "MGET")?;
+            let values = store::fetch_many(&keys).map_err(storage_error)?;
+
+            Command::MGet {
+                entries: keys.into_iter().zip(values).collect(),
+            }
+        }
+        Some("LIST") => {
+            let all_entries = store::list_all().map_err(storage_error)?;
+            Command::List {
+                entries: all_entries,
+            }
+        }
+        Some("RANGE") => {
+            let (start, end) = match (request.get("start"), request.get("end")) {
+                (Some(start), Some(end)) => (value::from_json(start)?, value::from_json(end)?),
+                _ => return Err("Invalid range parameters".to_string()),
+            };
+
+            let entries = store::get_range(&start, &end).map_err(storage_error)?;
+            Command::Range {
+                start,
+                end,
+                result: entries,
+            }
+        }
+        Some("SCAN") => {
+            let (start, end) = match (request["start"].as_str(), request["end"].as_str()) {
+                (Some(start), Some(end)) => (start, end),
+                _ => return Err("Invalid scan parameters".to_string()),
+            };
+            let limit = parse_limit(&request["limit"])?;
+            let inclusive = request["inclusive"].as_bool().unwrap_or(true);
+            let rev = request["rev"].as_bool().unwrap_or(false);
+
+            let entries =
+                store::scan_keys(start, end, inclusive, rev, limit).map_err(storage_error)?;
+            Command::Scan {
+                start: start.to_string(),
+                end: end.to_string(),
+                result: entries,
+            }
+        }
+        Some("PREFIX") => {
+            let prefix = request["prefix"]
+                .as_str()
+                .ok_or_else(|| "Unable to get prefix from request".to_string())?;
+            let limit = parse_limit(&request["limit"])?;
+
+            let entries = store::scan_prefix(prefix, limit).map_err(storage_error)?;
+            Command::Prefix {
+                prefix: prefix.to_string(),
+                result: entries,
+            }
+        }
+        Some("TTL") => {
+            let key = key_field(request)?;
+
+            match store::ttl(key.to_string()).map_err(storage_error)? {
+                Some(remaining) => Command::Ttl {
+                    key: key.to_string(),
+                    // round up so that a key that is still there never reports 0
+                    ttl: remaining.map(|ms| ms.div_ceil(1000)),
+                },
+                None => return Err("Key not found in storage!".to_string()),
+            }
+        }
+        Some("SNAPSHOT") => {
+            let lsn = store::save_store(snapshot::SNAPSHOT_DIR).map_err(storage_error)?;
+            Command::Snapshot { lsn }
+        }
+        _ => return Err("unknown command".to_string()),
+    };
+    Ok(command)
+}
+
+/// Builds the command for a write request without running it, None if it is not a write
+fn parse_write(request: &Value) -> Result<Option<Command>, String> {
+    let command = match request["command"].as_str() {
+        Some("STORE") => {
+            let (key, value) = match (request["key"].as_str(), request.get("value")) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err("Unable to read key_value pair from the request".to_string()),
+            };
+            let value = value::from_json(value)?;
+            let expires_at = parse_expiry(&request["ex"])?;
+
+            match parse_condition(request)? {
+                None => Command::Store {
+                    key: key.to_string(),
+                    value,
+                    expires_at,
+                },
+                Some(condition) => Command::StoreIf {
+                    key: key.to_string(),
+                    value,
+                    expires_at,
+                    condition,
+                    stored: None,
+                },
+            }
+        }
+        Some("GETSET") => {
+            let (key, value) = match (request["key"].as_str(), request.get("value")) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err("Unable to read key_value pair from the request".to_string()),
+            };
+
+            Command::GetSet {
+                key: key.to_string(),
+                value: value::from_json(value)?,
+                old: None,
+            }
+        }
+        Some("DELETE") => {
+            let key = request["key"]
+                .as_str()
+                .ok_or_else(|| "Error while removing the key".to_string())?;
+
+            Command::Delete {
+                key: key.to_string(),
+            }
+        }
+        Some("UPDATE") => {
+            let (key, value) = match (request["key"].as_str(), request.get("value")) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err("Error updating value".to_string()),
+            };
+            let value = value::from_json(value)?;
+
+            Command::Update {
+                key: key.to_string(),
+                value,
+            }
+        }
+        Some("EXPIRE") => {
+            let key = key_field(request)?;
+            let expires_at = parse_expiry(&request["seconds"])?
+                .ok_or_else(|| "Missing expiry seconds".to_string())?;
+
+            Command::Expire {
+                key: key.to_string(),
+                expires_at,
+            }
+        }
+        Some("PERSIST") => {
+            let key = key_field(request)?;
+
+            Command::Persist {
+                key: key.to_string(),
+            }
+        }
+        Some(name @ ("INCR" | "DECR" | "INCRBY" | "DECRBY")) => {
+            let key = key_field(request)?;
+            let by = match name {
+                "INCR" => 1,
+                "DECR" => -1,
+                _ => {
+                    let by = request["by"]
+                        .as_i64()
+                        .ok_or_else(|| format!("{} needs an integer to add", name))?;
+                    if name == "DECRBY" {
+                        by.checked_neg()
+                            .ok_or_else(|| "Decrement would underflow".to_string())?
+                    } else {
+                        by
+                    }
+                }
+            };
+
+            Command::Incr {
+                key: key.to_string(),
+                by,
+                value: None,
+                expires_at: None,
+            }
+        }
+        Some("MSET") => {
+            let entries = request["entries"]
+                .as_array()
+                .filter(|entries| !entries.is_empty())
+                .ok_or_else(|| "MSET needs a list of [key, value] pairs".to_string())?
+                .iter()
+                .map(|pair| match pair.as_array().map(Vec::as_slice) {
+                    Some([Value::String(key), value]) => {
+                        Ok((key.clone(), value::from_json(value)?))
+                    }
+                    _ => Err("MSET needs a list of [key, value] pairs".to_string()),
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+
+            Command::MSet { entries }
+        }
+        Some("MDEL") => Command::MDel {
+            keys: keys_field(request, "MDEL")?,
+            deleted: None,
+        },
+        Some("CAS") => {
+            let key = key_field(request)?;
+            let (expected, value) = match (request.get("expected"), request.get("value")) {
+                (Some(expected), Some(value)) => {
+                    (value::from_json(expected)?, value::from_json(value)?)
+                }
+                _ => return Err("CAS needs an expected and a new value".to_string()),
+            };
+
+            Command::Cas {
+                key: key.to_string(),
+                expected,
+                value,
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(command))
+}
+
+fn key_field(request: &Value) -> Result<&str, String> {
+    request["key"]
+        .as_str()
+        .ok_or_else(|| "Unable to get key from request".to_string())
+}
+
+/// Reads the list of keys of a multi-key command, which can not be empty
+fn keys_field(request: &Value, name: &str) -> Result<Vec<String>, String> {
+    request["keys"]
+        .as_array()
+        .and_then(|keys| {
+            keys.iter()
+                .map(|key| key.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+        })
+        .filter(|keys| !keys.is_empty())
+        .ok_or_else(|| format!("{} needs a list of keys", name))
+}
+
+fn storage_error(e: io::Error) -> String {
+    format!("Storage error: {}", e)
+}
+
+/// Turns a number of seconds from the request into an absolute expiry deadline
+///
+/// returns Ok(None) if the field is absent
+fn parse_expiry(seconds: &Value) -> Result<Option<u64>, String> {
+    if seconds.is_null() {
+        return Ok(None);
+    }
+
+    // an expiry of 0 would store a key that is gone right away
+    seconds
+        .as_u64()
+        .filter(|secs| *secs > 0)
+        .map(|secs| Some(store::now_millis().saturating_add(secs.saturating_mul(1000))))
+        .ok_or_else(|| "Expiry must be a positive number of seconds".to_string())
+}
+
+/// Reads the optional NX / XX flag of a STORE
+fn parse_condition(request: &Value) -> Result<Option<Condition>, String> {
+    let flag = |name: &str| match &request[name] {
+        Value::Null => Ok(false),
+        Value::Bool(set) => Ok(*set),
+        _ => Err(format!("{} must be true or false", name)),
+    };
+
+    match (flag("nx")?, flag("xx")?) {
+        (false, false) => Ok(None),
+        (true, false) => Ok(Some(Condition::NX)),
+        (false, true) => Ok(Some(Condition::XX)),
+        (true, true) => Err("NX and XX can not be used together".to_string()),
+    }
+}
+
+/// Reads the optional LIMIT of a scan
+fn parse_limit(limit: &Value) -> Result<Option<usize>, String> {
+    if limit.is_null() {
+        return Ok(None);
+    }
+
+    limit
+        .as_u64()
+        .map(|n| Some(n as usize))
+        .ok_or_else(|| "Limit must be a non-negative number".to_string())
+}
+
+fn handle_admin() {
+    let stdin = io::stdin();
+    let mut reader = BufReader::new(stdin.lock());
+    // lock the current standard input so that no other process could read from it ..
+
+    let mut admin_line = String::new();
+
+    eprintln!("Admin interface started!");
+
+    loop {
+        admin_line.clear();
+        print!("roc-admin/~  ");
+        io::stdout().flush().ok();
+
+        if reader.read_line(&mut admin_line).is_ok() {
+            let admin_cmd = admin_line.trim();
+            // eprintln!("Admin command received: {:#?}", admin_cmd);
+
+            if admin_cmd.eq_ignore_ascii_case("SHUTDOWN") {
+                if let Err(e) = store::save_store(snapshot::SNAPSHOT_DIR) {
+                    eprintln!("Failed to save the snapshot on shutdown: {}", e);
+                }
+                if let Err(e) = logger::sync_wal() {
+                    eprintln!("Failed to sync the WAL on shutdown: {}", e);
+                }
+
+                if let Err(e) = recovery::release_lock() {
+                    eprintln!("Failed to release the lock file: {}", e);
+                }
+                eprintln!("SHUTDOWN initiated!");
+                std::process::exit(0);
+            }
+            if admin_cmd.eq_ignore_ascii_case("CRASH") {
+                // exit with the lock file still marked, just like a kill would
+                eprintln!("Simulated CRASH initiated for testing recovery");
+                std::process::exit(0);
+            }
+
+            if admin_cmd.eq_ignore_ascii_case("snap") {
+                let _ = store::save_store(snapshot::SNAPSHOT_DIR);
+            }
+
+            if admin_cmd.eq_ignore_ascii_case("clear wal") {
+                // dropping the WAL without a snapshot would lose writes, so take one first --
+                // that removes every segment it covers
+                let _ = store::save_store(snapshot::SNAPSHOT_DIR);
+            }
+        } else {
+            eprintln!("failed to read command from the admin");
+        }
+    }
+}
@@ -1,16 +1,934 @@
-use crate::store;
-use std::path::Path;
+use crate::codec;
+use crate::config::{Compression, SnapshotFormat, SnapshotPolicy};
+use crate::logger;
+use crate::store::{self, Entry};
+use crate::value::{self, Value};
+use bincode::Options;
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::Duration;
 
-pub fn take_snapshots<P: AsRef<Path> + Send + 'static>(snapshot_path: P, interval_secs: u64) {
+// A snapshot is a file snapshot-<last lsn>.json (or .bin) in the snapshot directory: one line
+// with the JSON header, then the entries. The header checksum covers everything after the first
+// line.
+//
+// The header says how the entries are written:
+//
+//  json    -- one JSON object, key -> entry
+//  bincode -- the (key, entry) pairs one after another, as many as the header counts
+//
+// Either way the entries are read back one at a time as they are decoded and go straight into
+// the storage engine, so loading a big snapshot does not need all of it in memory.
+//
+// The body can also be compressed and encrypted (see codec.rs), the header says so. The header
+// itself stays readable, it holds nothing but numbers. Version 1 encoded the body in one piece
+// with the last LSN as the associated data; since version 2 it is cut into CHUNK_SIZE pieces,
+// each written as (u32 length, encoded piece) with the last LSN and the number of the piece as
+// the associated data, so that a snapshot can be written without holding all of it in memory.
+//
+// The entries are only counted once they are written, so the header line is padded out to
+// HEADER_SPACE and filled in last.
+
+/// where the snapshots are saved and loaded from
+pub(crate) const SNAPSHOT_DIR: &str = "../snaps";
+
+const SNAPSHOT_VERSION: u32 = 2;
+
+// how much of the body is encoded at a time
+const CHUNK_SIZE: usize = 1024 * 1024;
+// the header line, padded with spaces
+const HEADER_SPACE: usize = 1024;
+
+/// the single snapshot file older versions kept, still loaded if there is nothing newer
+const LEGACY_SNAPSHOT: &str = "snapshots.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SnapshotHeader {
+    pub(crate) version: u32,
+    /// unix time in milliseconds
+    pub(crate) created_at: u64,
+    pub(crate) entries: u64,
+    /// crc32 of the body
+    pub(crate) checksum: u32,
+    /// every WAL record up to this LSN is in the snapshot
+    pub(crate) last_lsn: u64,
+    /// how the entries are written, snapshots from before there was a choice are JSON
+    #[serde(default)]
+    pub(crate) format: SnapshotFormat,
+    #[serde(default)]
+    pub(crate) compression: Compression,
+    /// sealed with the key from ROC_KEY_FILE
+    #[serde(default)]
+    pub(crate) encrypted: bool,
+}
+
+/// Reads through to inner, keeping a crc32 of everything that went by
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Starts the thread taking snapshots into snapshot_dir whenever the policy says so
+pub fn take_snapshots<P: AsRef<Path> + Send + 'static>(snapshot_dir: P, policy: SnapshotPolicy) {
     // AsRef helps here to accept different kinda parameters that can be made into a Path variable
     // Send helps us tell that any variables produced within this thread can be safely transferred
     // to any other thread ...
 
     thread::spawn(move || loop {
-        thread::sleep(Duration::from_secs(interval_secs));
+        thread::sleep(Duration::from_millis(100));
+
+        // counted from the last snapshot, whoever took it
+        let saved = store::last_saved();
+        let writes = store::applied_lsn().saturating_sub(saved.lsn);
+        if writes == 0 {
+            // nothing new to save
+            continue;
+        }
+
+        let due = policy
+            .interval_secs
+            .is_some_and(|secs| saved.at.elapsed() >= Duration::from_secs(secs))
+            || policy.writes.is_some_and(|n| writes >= n)
+            || policy
+                .wal_bytes
+                .is_some_and(|bytes| logger::bytes_written() - saved.wal_bytes >= bytes);
+        if !due {
+            continue;
+        }
 
-        store::save_store(&snapshot_path).expect("Failed to save the periodic snapshot");
+        if let Err(e) = store::save_store(&snapshot_dir) {
+            eprintln!("Failed to save the scheduled snapshot: {}", e);
+            // give whatever is wrong a moment instead of trying again right away
+            thread::sleep(Duration::from_secs(1));
+        }
     });
 }
+
+fn extension(format: SnapshotFormat) -> &'static str {
+    match format {
+        SnapshotFormat::Json => "json",
+        SnapshotFormat::Bincode => "bin",
+    }
+}
+
+fn snapshot_path(dir: &Path, last_lsn: u64, format: SnapshotFormat) -> PathBuf {
+    dir.join(format!("snapshot-{:020}.{}", last_lsn, extension(format)))
+}
+
+/// All the snapshots in dir as (last lsn, path), oldest first
+pub(crate) fn list_snapshots(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut snapshots = Vec::new();
+
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(snapshots),
+        Err(e) => return Err(e),
+    };
+    for file in files {
+        let path = file?.path();
+        let lsn = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("snapshot-"))
+            .and_then(|name| {
+                name.strip_suffix(".json")
+                    .or_else(|| name.strip_suffix(".bin"))
+            })
+            .and_then(|lsn| lsn.parse::<u64>().ok());
+        if let Some(lsn) = lsn {
+            snapshots.push((lsn, path));
+        }
+    }
+
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+/// Writes the body of a snapshot -- straight through if it is plain, in encoded chunks if it is
+/// compressed or encrypted -- and keeps a crc32 of what reaches the file
+struct BodyWriter<W> {
+    out: W,
+    hasher: crc32fast::Hasher,
+    /// how the chunks are encoded, None for a plain body
+    codec: Option<(Compression, Option<&'static ChaCha20Poly1305>)>,
+    last_lsn: u64,
+    chunk: Vec<u8>,
+    chunks: u64,
+}
+
+impl<W: Write> BodyWriter<W> {
+    fn emit(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        self.out.write_all(data)
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        let Some((compression, cipher)) = self.codec else {
+            return Ok(());
+        };
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+
+        let mut aad = self.last_lsn.to_le_bytes().to_vec();
+        aad.extend_from_slice(&self.chunks.to_le_bytes());
+        let encoded = codec::encode(&self.chunk, compression, cipher, &aad)?;
+        self.chunk.clear();
+        self.chunks += 1;
+
+        self.emit(&(encoded.len() as u32).to_le_bytes())?;
+        self.emit(&encoded)
+    }
+
+    /// writes what is left, returns the output and the checksum of the body
+    fn finish(mut self) -> io::Result<(W, u32)> {
+        self.write_chunk()?;
+        Ok((self.out, self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for BodyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.codec.is_none() {
+            self.emit(buf)?;
+            return Ok(buf.len());
+        }
+
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes the entries, which come in key order, as a new snapshot in dir
+///
+/// They are written out as they come, the snapshot is never all in memory. The file is written
+/// to the side, synced and then renamed into place, so a crash leaves either the complete
+/// snapshot or none at all.
+pub(crate) fn write_snapshot(
+    dir: &Path,
+    entries: &mut dyn Iterator<Item = io::Result<(String, Entry)>>,
+    last_lsn: u64,
+    format: SnapshotFormat,
+    compression: Compression,
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let path = snapshot_path(dir, last_lsn, format);
+    let tmp = path.with_extension(format!("{}.tmp", extension(format)));
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    file.write_all(&[b' '; HEADER_SPACE])?;
+    file.write_all(b"\n")?;
+
+    let cipher = codec::cipher()?;
+    let coded = compression != Compression::None || cipher.is_some();
+    let mut body = BodyWriter {
+        out: file,
+        hasher: crc32fast::Hasher::new(),
+        codec: coded.then_some((compression, cipher)),
+        last_lsn,
+        chunk: Vec::new(),
+        chunks: 0,
+    };
+
+    let mut count = 0;
+    match format {
+        SnapshotFormat::Json => {
+            let mut serializer = serde_json::Serializer::new(&mut body);
+            let mut map = serializer.serialize_map(None).map_err(io::Error::other)?;
+            for item in entries {
+                let (key, entry) = item?;
+                map.serialize_entry(&key, &entry)
+                    .map_err(io::Error::other)?;
+                count += 1;
+            }
+            SerializeMap::end(map).map_err(io::Error::other)?;
+        }
+        SnapshotFormat::Bincode => {
+            for item in entries {
+                bincode::serialize_into(&mut body, &item?).map_err(io::Error::other)?;
+                count += 1;
+            }
+        }
+    }
+    let (file, checksum) = body.finish()?;
+
+    let header = SnapshotHeader {
+        version: SNAPSHOT_VERSION,
+        created_at: store::now_millis(),
+        entries: count,
+        checksum,
+        last_lsn,
+        format,
+        compression,
+        encrypted: cipher.is_some(),
+    };
+    let header = serde_json::to_vec(&header).map_err(io::Error::other)?;
+    if header.len() > HEADER_SPACE {
+        return Err(io::Error::other("snapshot header does not fit"));
+    }
+
+    let mut file = file.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, &path)?;
+    // make the rename itself durable
+    File::open(dir)?.sync_all()?;
+
+    Ok(path)
+}
+
+/// Reads the body of a version 2 snapshot one chunk at a time, decoded, see the top of this file
+struct ChunkReader<'a, R> {
+    inner: R,
+    header: &'a SnapshotHeader,
+    cipher: Option<&'a ChaCha20Poly1305>,
+    /// a damaged chunk length can not make us allocate more than the file holds
+    body_size: u64,
+    chunk: u64,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> ChunkReader<'_, R> {
+    /// Decodes the next chunk, false at the end of the body
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        // the body has to end between two chunks
+        let mut len = [0; 4];
+        if self.inner.read(&mut len[..1])? == 0 {
+            return Ok(false);
+        }
+        self.inner
+            .read_exact(&mut len[1..])
+            .map_err(|_| invalid("truncated chunk length"))?;
+        let len = u32::from_le_bytes(len) as u64;
+        if len > self.body_size {
+            return Err(invalid("truncated chunk"));
+        }
+        let mut data = vec![0; len as usize];
+        self.inner
+            .read_exact(&mut data)
+            .map_err(|_| invalid("truncated chunk"))?;
+
+        let mut aad = self.header.last_lsn.to_le_bytes().to_vec();
+        aad.extend_from_slice(&self.chunk.to_le_bytes());
+        self.plain = codec::decode(&data, self.header.compression, self.cipher, &aad)?;
+        self.pos = 0;
+        self.chunk += 1;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for ChunkReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The entries of a snapshot body as they are decoded, a damaged one ends them
+struct Decoded<F> {
+    decode: F,
+    count: u64,
+    error: Option<String>,
+}
+
+impl<F: FnMut() -> Result<Option<(String, Entry)>, String>> Iterator for Decoded<F> {
+    type Item = (String, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        match (self.decode)() {
+            Ok(Some(entry)) => {
+                self.count += 1;
+                Some(entry)
+            }
+            Ok(None) => None,
+            Err(msg) => {
+                self.error = Some(msg);
+                None
+            }
+        }
+    }
+}
+
+impl<F> Decoded<F> {
+    fn new(decode: F) -> Self {
+        Decoded {
+            decode,
+            count: 0,
+            error: None,
+        }
+    }
+}
+
+/// What the entries of a snapshot are handed to, in key order, as they are read
+pub(crate) type Load<'a> =
+    &'a mut dyn FnMut(&SnapshotHeader, &mut dyn Iterator<Item = (String, Entry)>) -> io::Result<()>;
+
+/// How loading the entries went: the outcome of load, and how many there were or why the rest
+/// could not be read
+type Loaded = (io::Result<()>, u64, Option<String>);
+
+/// Hands the entries of a JSON body to load while serde_json reads them
+struct JsonEntries<'a, 'b> {
+    header: &'a SnapshotHeader,
+    load: Load<'b>,
+    loaded: &'a mut Option<Loaded>,
+}
+
+impl<'de> Visitor<'de> for JsonEntries<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of keys to entries")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut entries =
+            Decoded::new(|| map.next_entry().map_err(|e| format!("bad entries: {}", e)));
+        let loaded = (self.load)(self.header, &mut entries);
+        if loaded.is_ok() {
+            entries.by_ref().for_each(drop);
+        }
+        *self.loaded = Some((loaded, entries.count, entries.error));
+        Ok(())
+    }
+}
+
+/// A snapshot with its header read, the entries are next
+struct SnapshotFile {
+    header: SnapshotHeader,
+    reader: BufReader<File>,
+    body_size: u64,
+}
+
+/// Opens the snapshot at path and reads its header
+fn open_snapshot(path: &Path) -> Result<SnapshotFile, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let size = file.metadata().map_err(|e| e.to_string())?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut header = Vec::new();
+    reader
+        .read_until(b'\n', &mut header)
+        .map_err(|e| e.to_string())?;
+    if header.pop() != Some(b'\n') {
+        return Err("missing header".to_string());
+    }
+    let body_size = size.saturating_sub(header.len() as u64 + 1);
+
+    let header: SnapshotHeader =
+        serde_json::from_slice(&header).map_err(|e| format!("bad header: {}", e))?;
+    if !(1..=SNAPSHOT_VERSION).contains(&header.version) {
+        return Err(format!("unsupported snapshot version {}", header.version));
+    }
+
+    Ok(SnapshotFile {
+        header,
+        reader,
+        body_size,
+    })
+}
+
+/// Reads the entries of a snapshot into load and verifies them
+///
+/// Only a chunk of the body is in memory at a time. That also means the count and the checksum
+/// can only be checked once load went through the entries -- load may get entries of a snapshot
+/// that turns out damaged, putting something else in their place is up to the caller.
+///
+/// The outer error is one of load, the inner one says what is wrong with the snapshot.
+fn load_entries(file: SnapshotFile, load: Load) -> io::Result<Result<SnapshotHeader, String>> {
+    let SnapshotFile {
+        header,
+        reader,
+        body_size,
+    } = file;
+    let mut raw = ChecksumReader {
+        inner: reader,
+        hasher: crc32fast::Hasher::new(),
+    };
+
+    let cipher = match header.encrypted {
+        true => match codec::required_cipher() {
+            Ok(cipher) => Some(cipher),
+            Err(e) => return Ok(Err(e.to_string())),
+        },
+        false => None,
+    };
+    let plain = header.compression == Compression::None && !header.encrypted;
+    // the most a single entry can take up -- each one was written by a single logged write
+    let mut limit = logger::MAX_BODY as u64;
+    let mut body: Box<dyn Read + '_> = match header.version {
+        _ if plain => {
+            limit = body_size;
+            Box::new(BufReader::new(&mut raw))
+        }
+        // version 1 encoded the body in one piece, there is no way around reading all of it
+        1 => {
+            let mut data = Vec::new();
+            if let Err(e) = raw.read_to_end(&mut data) {
+                return Ok(Err(e.to_string()));
+            }
+            let aad = header.last_lsn.to_le_bytes();
+            match codec::decode(&data, header.compression, cipher, &aad) {
+                Ok(data) => Box::new(io::Cursor::new(data)),
+                Err(e) => return Ok(Err(e.to_string())),
+            }
+        }
+        _ => Box::new(BufReader::new(ChunkReader {
+            inner: &mut raw,
+            header: &header,
+            cipher,
+            body_size,
+            chunk: 0,
+            plain: Vec::new(),
+            pos: 0,
+        })),
+    };
+
+    let mut loaded = None;
+    let finished = match header.format {
+        SnapshotFormat::Json => {
+            let mut json = serde_json::Deserializer::from_reader(&mut body);
+            let visitor = JsonEntries {
+                header: &header,
+                load,
+                loaded: &mut loaded,
+            };
+            json.deserialize_map(visitor)
+                .and_then(|_| json.end())
+                .map_err(|e| format!("bad entries: {}", e))
+        }
+        SnapshotFormat::Bincode => {
+            let options = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(limit);
+            let mut left = header.entries;
+            let mut entries = Decoded::new(|| {
+                if left == 0 {
+                    return Ok(None);
+                }
+                left -= 1;
+                options
+                    .deserialize_from(&mut body)
+                    .map(Some)
+                    .map_err(|e| format!("bad entry {}: {}", header.entries - left - 1, e))
+            });
+            let result = load(&header, &mut entries);
+            if result.is_ok() {
+                entries.by_ref().for_each(drop);
+            }
+            loaded = Some((result, entries.count, entries.error));
+
+            let mut rest = [0; 1];
+            match body.read(&mut rest) {
+                Ok(0) => Ok(()),
+                Ok(_) => Err(format!(
+                    "more than the {} entries in the header",
+                    header.entries
+                )),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+    };
+    drop(body);
+
+    let (count, error) = match loaded {
+        Some((result, count, error)) => {
+            result?;
+            (count, error)
+        }
+        None => (0, None),
+    };
+    if let Some(msg) = error {
+        return Ok(Err(msg));
+    }
+    if let Err(msg) = finished {
+        return Ok(Err(msg));
+    }
+    if count != header.entries {
+        return Ok(Err(format!(
+            "expected {} entries, found {}",
+            header.entries, count
+        )));
+    }
+    if raw.hasher.finalize() != header.checksum {
+        return Ok(Err("checksum mismatch".to_string()));
+    }
+    Ok(Ok(header))
+}
+
+/// Reads and verifies the snapshot at path, with all the entries in memory -- for the tool
+pub(crate) fn read_snapshot(
+    path: &Path,
+) -> Result<(SnapshotHeader, BTreeMap<String, Entry>), String> {
+    let mut entries = BTreeMap::new();
+    let header = load_entries(open_snapshot(path)?, &mut |_, read| {
+        entries.extend(read);
+        Ok(())
+    })
+    .map_err(|e| e.to_string())??;
+    Ok((header, entries))
+}
+
+/// Reads and verifies the snapshot at path without keeping the entries
+pub(crate) fn verify_snapshot(path: &Path) -> Result<SnapshotHeader, String> {
+    load_entries(open_snapshot(path)?, &mut |_, read| {
+        read.for_each(drop);
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?
+}
+
+/// Loads the newest snapshot in dir that is intact into load, falling back to older ones
+///
+/// returns None if there is no snapshot at all, an error if there are some but none of them
+/// can be read -- starting empty then would silently throw the data away.
+pub(crate) fn read_latest(dir: &Path, load: Load) -> io::Result<Option<SnapshotHeader>> {
+    if list_snapshots(dir)?.is_empty() {
+        let Some((header, entries)) = read_legacy(dir)? else {
+            return Ok(None);
+        };
+        load(&header, &mut entries.into_iter())?;
+        return Ok(Some(header));
+    }
+
+    match read_matching(dir, &|_| true, load)? {
+        Some(header) => Ok(Some(header)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "none of the snapshots could be read",
+        )),
+    }
+}
+
+/// Loads the newest intact snapshot in dir that pick accepts into load, None if there is none
+///
+/// A damaged snapshot may have handed some of its entries to load before the next one is
+/// tried, see `load_entries`.
+pub(crate) fn read_matching(
+    dir: &Path,
+    pick: &dyn Fn(&SnapshotHeader) -> bool,
+    load: Load,
+) -> io::Result<Option<SnapshotHeader>> {
+    for (_, path) in list_snapshots(dir)?.iter().rev() {
+        let loaded = match open_snapshot(path) {
+            Ok(file) if pick(&file.header) => {
+                eprintln!("Loading snapshot {:?}", path);
+                load_entries(file, load)?
+            }
+            Ok(_) => continue,
+            Err(msg) => Err(msg),
+        };
+        match loaded {
+            Ok(header) => return Ok(Some(header)),
+            Err(msg) => eprintln!("Skipping damaged snapshot {:?}: {}", path, msg),
+        }
+    }
+    Ok(None)
+}
+
+/// Moves the snapshots holding anything past lsn into archive, returns how many there were
+pub(crate) fn archive_snapshots_after(dir: &Path, lsn: u64, archive: &Path) -> io::Result<usize> {
+    let newer: Vec<_> = list_snapshots(dir)?
+        .into_iter()
+        .filter(|(last_lsn, _)| *last_lsn > lsn)
+        .collect();
+
+    fs::create_dir_all(archive)?;
+    for (_, path) in &newer {
+        if let Some(name) = path.file_name() {
+            fs::rename(path, archive.join(name))?;
+        }
+    }
+    Ok(newer.len())
+}
+
+/// A value in snapshots.json, which went through a few shapes before it was replaced
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyEntry {
+    /// the first versions only had unsigned numbers
+    Number(u64),
+    /// then typed values
+    Value(Value),
+    /// then values with an expiry
+    Entry(Entry),
+}
+
+/// The old headerless snapshots.json, it has no LSN so the whole WAL applies on top of it
+fn read_legacy(dir: &Path) -> io::Result<Option<(SnapshotHeader, BTreeMap<String, Entry>)>> {
+    let data = match fs::read_to_string(dir.join(LEGACY_SNAPSHOT)) {
+        Ok(data) if !data.trim().is_empty() => data,
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let legacy: BTreeMap<String, LegacyEntry> =
+        serde_json::from_str(&data).map_err(|e| invalid(e.to_string()))?;
+    eprintln!("Loading legacy snapshot {:?}", dir.join(LEGACY_SNAPSHOT));
+
+    let entries = legacy
+        .into_iter()
+        .map(|(key, entry)| {
+            let entry = match entry {
+                LegacyEntry::Number(n) => Entry {
+                    value: value::from_legacy(n).map_err(&invalid)?,
+                    expires_at: None,
+                },
+                LegacyEntry::Value(value) => Entry {
+                    value,
+                    expires_at: None,
+                },
+                LegacyEntry::Entry(entry) => entry,
+            };
+            Ok((key, entry))
+        })
+        .collect::<io::Result<BTreeMap<String, Entry>>>()?;
+
+    let header = SnapshotHeader {
+        version: 0,
+        entries: entries.len() as u64,
+        ..Default::default()
+    };
+    Ok(Some((header, entries)))
+}
+
+/// Deletes all but the newest `keep` snapshots (and any leftover temp files)
+///
+/// returns the LSN of the oldest snapshot kept -- the WAL has to go back that far for the
+/// fallback to work. Once that is past LSN 0 the WAL snapshots.json needs goes away, and so does
+/// snapshots.json.
+pub(crate) fn prune_snapshots(dir: &Path, keep: usize) -> io::Result<u64> {
+    let snapshots = list_snapshots(dir)?;
+    let cut = snapshots.len().saturating_sub(keep);
+    for (_, path) in &snapshots[..cut] {
+        fs::remove_file(path)?;
+    }
+    if snapshots.get(cut).is_some_and(|(lsn, _)| *lsn > 0) {
+        match fs::remove_file(dir.join(LEGACY_SNAPSHOT)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        if path.to_str().is_some_and(|p| p.ends_with(".tmp")) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    Ok(snapshots.get(cut).map_or(0, |(lsn, _)| *lsn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fresh directory under the system temp dir
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rocs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// read_latest into a map, replaced by every snapshot it tries like the engine is
+    fn read_latest_map(
+        dir: &Path,
+    ) -> io::Result<Option<(SnapshotHeader, BTreeMap<String, Entry>)>> {
+        let mut entries = BTreeMap::new();
+        let header = read_latest(dir, &mut |_, read| {
+            entries = read.collect();
+            Ok(())
+        })?;
+        Ok(header.map(|header| (header, entries)))
+    }
+
+    #[test]
+    fn loads_a_baseline_snapshot() {
+        let dir = test_dir("baseline-snapshot");
+        // written by the baseline save_store, values were plain usize
+        fs::write(dir.join(LEGACY_SNAPSHOT), r#"{"a":12,"b":0}"#).unwrap();
+
+        let (header, entries) = read_latest_map(&dir).unwrap().unwrap();
+        assert_eq!(header.last_lsn, 0);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["a"].value, Value::Int(12));
+        assert_eq!(entries["a"].expires_at, None);
+        assert_eq!(entries["b"].value, Value::Int(0));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loads_typed_legacy_snapshots() {
+        let dir = test_dir("typed-snapshot");
+        fs::write(
+            dir.join(LEGACY_SNAPSHOT),
+            r#"{"a":{"Str":"x"},"b":{"value":{"Int":3},"expires_at":99}}"#,
+        )
+        .unwrap();
+
+        let (_, entries) = read_latest_map(&dir).unwrap().unwrap();
+        assert_eq!(entries["a"].value, Value::Str("x".to_string()));
+        assert_eq!(entries["b"].value, Value::Int(3));
+        assert_eq!(entries["b"].expires_at, Some(99));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_legacy_value_that_does_not_fit() {
+        let dir = test_dir("huge-snapshot");
+        fs::write(dir.join(LEGACY_SNAPSHOT), r#"{"a":18446744073709551615}"#).unwrap();
+
+        let e = read_latest_map(&dir).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// enough entries to fill a few chunks
+    fn many_entries() -> BTreeMap<String, Entry> {
+        (0..40_000)
+            .map(|i| {
+                let entry = Entry {
+                    value: Value::Str(format!("value {} {}", i, "x".repeat(i % 50))),
+                    expires_at: (i % 7 == 0).then_some(i as u64),
+                };
+                (format!("key{:06}", i), entry)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streams_every_format_back_and_forth() {
+        let dir = test_dir("stream-snapshot");
+        let entries = many_entries();
+
+        let mut lsn = 0;
+        for format in [SnapshotFormat::Json, SnapshotFormat::Bincode] {
+            for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+                lsn += 1;
+                let mut view = entries.clone().into_iter().map(Ok);
+                let path = write_snapshot(&dir, &mut view, lsn, format, compression).unwrap();
+
+                let (header, read) = read_snapshot(&path).unwrap();
+                assert_eq!(header.entries, entries.len() as u64);
+                assert_eq!(header.last_lsn, lsn);
+                assert_eq!(read, entries, "{:?} {:?}", format, compression);
+            }
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn falls_back_from_a_snapshot_found_damaged_while_loading() {
+        let dir = test_dir("damaged-snapshot");
+        let entries = many_entries();
+        let older: BTreeMap<String, Entry> = entries.clone().into_iter().take(10).collect();
+
+        let mut view = older.clone().into_iter().map(Ok);
+        write_snapshot(&dir, &mut view, 1, SnapshotFormat::Json, Compression::Lz4).unwrap();
+        for format in [SnapshotFormat::Json, SnapshotFormat::Bincode] {
+            let mut view = entries.clone().into_iter().map(Ok);
+            let path = write_snapshot(&dir, &mut view, 2, format, Compression::Lz4).unwrap();
+            // damage the last chunk, the ones before it are loaded by then
+            let mut data = fs::read(&path).unwrap();
+            let last = data.len() - 10;
+            data[last] ^= 0xff;
+            fs::write(&path, data).unwrap();
+
+            let mut tried = 0;
+            let header = read_latest(&dir, &mut |_, read| {
+                tried += 1;
+                read.for_each(drop);
+                Ok(())
+            })
+            .unwrap()
+            .unwrap();
+            assert_eq!((header.last_lsn, tried), (1, 2), "{:?}", format);
+            let (_, read) = read_latest_map(&dir).unwrap().unwrap();
+            assert_eq!(read, older);
+
+            fs::remove_file(path).unwrap();
+        }
+
+        // an error of load itself is no reason to go on with an older snapshot
+        let e = read_latest(&dir, &mut |_, _| Err(io::Error::other("disk full"))).unwrap_err();
+        assert_eq!(e.to_string(), "disk full");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_a_version_1_compressed_snapshot() {
+        let dir = test_dir("v1-snapshot");
+        let entries = many_entries();
+
+        // the body encoded in one piece, the way version 1 wrote it
+        let body = serde_json::to_vec(&entries).unwrap();
+        let body = codec::encode(&body, Compression::Zstd, None, &7u64.to_le_bytes()).unwrap();
+        let header = SnapshotHeader {
+            version: 1,
+            entries: entries.len() as u64,
+            checksum: crc32fast::hash(&body),
+            last_lsn: 7,
+            compression: Compression::Zstd,
+            ..Default::default()
+        };
+        let path = snapshot_path(&dir, 7, SnapshotFormat::Json);
+        let mut data = serde_json::to_vec(&header).unwrap();
+        data.push(b'\n');
+        data.extend_from_slice(&body);
+        fs::write(&path, data).unwrap();
+
+        let (_, read) = read_snapshot(&path).unwrap();
+        assert_eq!(read, entries);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
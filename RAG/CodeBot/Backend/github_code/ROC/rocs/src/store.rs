@@ -1,83 +1,1011 @@
 // ROC/rocs/src/store.rs
 #![allow(dead_code)]
 
+use crate::command::{Command, Condition};
+use crate::config::CONFIG;
+use crate::engine::{MemoryEngine, StorageEngine};
 use crate::logger;
+use crate::snapshot;
+use crate::value::Value;
 
 use once_cell::sync::Lazy;
-// use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use std::fs;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::ops::Bound;
 use std::path::Path;
 // can support range queries now ..
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-static STORE: Lazy<RwLock<BTreeMap<String, usize>>> = Lazy::new(|| RwLock::new(BTreeMap::new()));
+/// A value in the store along with its expiry deadline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) value: Value,
+    /// unix time in milliseconds after which the key is gone -- None if it never expires
+    ///
+    /// this is an absolute deadline so that it means the same thing after a restart
+    #[serde(default)]
+    pub(crate) expires_at: Option<u64>,
+}
 
-pub(crate) fn store_values(key: String, value: usize) {
-    let mut db = STORE.write().unwrap();
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= now)
+    }
+}
 
-    db.insert(key, value); // passing the ownership
+/// The storage engine along with the indexes kept over it
+///
+/// Every change to the engine has to go through `insert` / `remove` so that the indexes stay
+/// consistent with it.
+struct Store {
+    engine: Box<dyn StorageEngine>,
+    /// value -> keys holding that value, so that RANGE queries do not walk the whole keyspace
+    ///
+    /// Only kept over the in-memory engine -- the disk engines can hold a lot more than fits
+    /// into memory, so RANGE scans those instead.
+    by_value: Option<BTreeMap<Value, BTreeSet<String>>>,
+    /// (deadline, key) of every key with an expiry, so the sweeper finds expired keys without a
+    /// scan -- kept over every engine, the deadlines are only on disk inside the entries
+    expiring: BTreeSet<(u64, String)>,
+    /// key -> its deadline in `expiring`
+    deadlines: HashMap<String, u64>,
+    /// the LSN of the last WAL record applied, the store holds exactly the log up to here
+    applied_lsn: u64,
+    /// key -> (number of WATCHes on it, version of its last change)
+    ///
+    /// Only watched keys are in here, and only as long as someone watches them -- the map is as
+    /// big as the open watches, not as the keyspace.
+    watched: HashMap<String, (usize, u64)>,
+    /// the last version handed out
+    last_version: u64,
 }
 
-pub(crate) fn fetch_values(key: String) -> Option<usize> {
+impl Store {
+    /// wraps an engine and builds the indexes over what is already in it
+    fn new(engine: Box<dyn StorageEngine>) -> io::Result<Self> {
+        let by_value = engine.persisted_lsn().is_none().then(BTreeMap::new);
+        let mut store = Store {
+            engine,
+            by_value,
+            expiring: BTreeSet::new(),
+            deadlines: HashMap::new(),
+            applied_lsn: 0,
+            watched: HashMap::new(),
+            last_version: 0,
+        };
+        store.reindex()?;
+        Ok(store)
+    }
+
+    /// With a disk engine that reads all of it once, for the deadlines
+    fn reindex(&mut self) -> io::Result<()> {
+        let mut by_value: Option<BTreeMap<Value, BTreeSet<String>>> =
+            self.by_value.is_some().then(BTreeMap::new);
+        let mut expiring = BTreeSet::new();
+        let mut deadlines = HashMap::new();
+        self.engine.iter(&mut |key, entry| {
+            if let Some(by_value) = &mut by_value {
+                by_value
+                    .entry(entry.value.clone())
+                    .or_default()
+                    .insert(key.to_string());
+            }
+            if let Some(deadline) = entry.expires_at {
+                expiring.insert((deadline, key.to_string()));
+                deadlines.insert(key.to_string(), deadline);
+            }
+            true
+        })?;
+
+        self.by_value = by_value;
+        self.expiring = expiring;
+        self.deadlines = deadlines;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Entry>> {
+        self.engine.get(key)
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) -> io::Result<()> {
+        self.set_deadline(&key, entry.expires_at);
+        if self.by_value.is_some() {
+            // only the memory engine has the index, reading the old entry there is cheap
+            if let Some(old) = self.engine.get(&key)? {
+                self.unindex(&key, &old.value);
+            }
+        }
+        if let Some(by_value) = &mut self.by_value {
+            by_value
+                .entry(entry.value.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+        self.engine.put(key.clone(), entry)?;
+        self.changed(&key);
+        Ok(())
+    }
+
+    /// Removes a key, the writes only ask for that when it is there
+    fn remove(&mut self, key: &str) -> io::Result<()> {
+        self.set_deadline(key, None);
+        if self.by_value.is_some() {
+            if let Some(old) = self.engine.get(key)? {
+                self.unindex(key, &old.value);
+            }
+        }
+        self.engine.delete(key)?;
+        self.changed(key);
+        Ok(())
+    }
+
+    /// Gives a watched key a new version, keys nobody watches have none
+    fn changed(&mut self, key: &str) {
+        if let Some((_, version)) = self.watched.get_mut(key) {
+            self.last_version += 1;
+            *version = self.last_version;
+        }
+    }
+
+    /// Starts tracking the changes of a key, returns its version
+    ///
+    /// A key that was not watched yet starts out with the last version handed out, every
+    /// change after this gets a higher one.
+    fn watch(&mut self, key: &str) -> u64 {
+        let last_version = self.last_version;
+        let (watches, version) = self
+            .watched
+            .entry(key.to_string())
+            .or_insert((0, last_version));
+        *watches += 1;
+        *version
+    }
+
+    /// Ends a `watch` of the key, it is forgotten once nobody watches it anymore
+    fn unwatch(&mut self, key: &str) {
+        if let Some((watches, _)) = self.watched.get_mut(key) {
+            *watches -= 1;
+            if *watches == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// The version of a watched key, it changes whenever the key is written or removed
+    ///
+    /// None for a key nobody watches -- that only happens when the store was replaced at
+    /// startup, and the caller takes it as a change.
+    fn version(&self, key: &str) -> Option<u64> {
+        self.watched.get(key).map(|(_, version)| *version)
+    }
+
+    fn set_deadline(&mut self, key: &str, deadline: Option<u64>) {
+        if let Some(old) = self.deadlines.remove(key) {
+            self.expiring.remove(&(old, key.to_string()));
+        }
+        if let Some(deadline) = deadline {
+            self.deadlines.insert(key.to_string(), deadline);
+            self.expiring.insert((deadline, key.to_string()));
+        }
+    }
+
+    /// Up to limit keys that expired by now, the longest expired first
+    fn expired_keys(&self, now: u64, limit: usize) -> Vec<String> {
+        self.expiring
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    fn unindex(&mut self, key: &str, value: &Value) {
+        let Some(by_value) = &mut self.by_value else {
+            return;
+        };
+        if let Some(keys) = by_value.get_mut(value) {
+            keys.remove(key);
+            if keys.is_empty() {
+                by_value.remove(value);
+            }
+        }
+    }
+}
+
+// everything starts out in memory, `set_engine` swaps in the configured engine at startup
+static STORE: Lazy<RwLock<Store>> = Lazy::new(|| {
+    RwLock::new(Store {
+        engine: Box::new(MemoryEngine::default()),
+        by_value: Some(BTreeMap::new()),
+        expiring: BTreeSet::new(),
+        deadlines: HashMap::new(),
+        applied_lsn: 0,
+        watched: HashMap::new(),
+        last_version: 0,
+    })
+});
+
+/// Puts the store on top of the given engine, whatever was stored before is dropped
+pub(crate) fn set_engine(engine: Box<dyn StorageEngine>) -> io::Result<()> {
+    let store = Store::new(engine)?;
+    *STORE.write().unwrap() = store;
+    Ok(())
+}
+
+/// current unix time in milliseconds
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn fetch_values(key: String) -> io::Result<Option<Value>> {
+    let now = now_millis();
+    {
+        let db = STORE.read().unwrap();
+
+        match db.get(&key)? {
+            Some(entry) if !entry.is_expired(now) => return Ok(Some(entry.value)),
+            Some(_) => {}
+            None => return Ok(None),
+        }
+    }
+
+    // the key has expired -- drop it while we are here instead of waiting for the sweeper
+    let mut db = STORE.write().unwrap();
+    if db.get(&key)?.is_some_and(|entry| entry.is_expired(now)) {
+        db.remove(&key)?;
+    }
+    Ok(None)
+}
+
+/// The values of many keys, all read under one lock -- None for keys that are not there
+pub(crate) fn fetch_many(keys: &[String]) -> io::Result<Vec<Option<Value>>> {
+    let now = now_millis();
     let db = STORE.read().unwrap();
 
-    db.get(&key).cloned() // just take the reference and return an OWNED value
+    // expired keys are left to the sweeper, dropping them here would need the write lock
+    keys.iter()
+        .map(|key| {
+            Ok(db
+                .get(key)?
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| entry.value))
+        })
+        .collect()
 }
 
-pub(crate) fn list_all() -> Vec<(String, usize)> {
+pub(crate) fn list_all() -> io::Result<Vec<(String, Value)>> {
+    let now = now_millis();
     let db = STORE.read().unwrap();
+
     // now we would get them in an sorted order
-    db.iter().map(|(k, &v)| (k.clone(), v)).collect()
+    let mut entries = Vec::new();
+    db.engine.iter(&mut |key, entry| {
+        if !entry.is_expired(now) {
+            entries.push((key.to_string(), entry.value.clone()));
+        }
+        true
+    })?;
+    Ok(entries)
 }
 
-pub(crate) fn delete_val(key: String) -> Option<usize> {
-    let mut db = STORE.write().unwrap();
+/// returns all entries whose value lies in [start, end], in key order
+///
+/// values of different types are ordered as described in `value::Value`
+pub(crate) fn get_range(start: &Value, end: &Value) -> io::Result<Vec<(String, Value)>> {
+    // BTreeMap::range panics on an inverted range
+    if start > end {
+        return Ok(Vec::new());
+    }
+
+    let now = now_millis();
+    let db = STORE.read().unwrap();
+
+    let mut result = Vec::new();
+    let by_value = match &db.by_value {
+        Some(by_value) => by_value,
+        // no index over the disk engines, the scan comes out in key order already
+        None => {
+            db.engine.iter(&mut |key, entry| {
+                if &entry.value >= start && &entry.value <= end && !entry.is_expired(now) {
+                    result.push((key.to_string(), entry.value.clone()));
+                }
+                true
+            })?;
+            return Ok(result);
+        }
+    };
+
+    let keys = by_value
+        .range::<Value, _>((Bound::Included(start), Bound::Included(end)))
+        .flat_map(|(_value, keys)| keys.iter());
+    for key in keys {
+        if let Some(entry) = db.get(key)?.filter(|entry| !entry.is_expired(now)) {
+            result.push((key.clone(), entry.value));
+        }
+    }
 
-    db.remove(&key)
+    result.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    Ok(result)
 }
 
-pub(crate) fn update_val(key: String, val: usize) {
-    let mut db = STORE.write().unwrap();
+/// Scans the keys between start and end in key order
+///
+/// Parameters:
+///
+/// > start: &str
+/// > end: &str
+/// > inclusive: bool (whether start and end themselves are part of the scan)
+/// > rev: bool (walk from end down to start)
+/// > limit: Option<usize> (maximum number of entries returned)
+pub(crate) fn scan_keys(
+    start: &str,
+    end: &str,
+    inclusive: bool,
+    rev: bool,
+    limit: Option<usize>,
+) -> io::Result<Vec<(String, Value)>> {
+    let (lower, upper) = if inclusive {
+        (Bound::Included(start), Bound::Included(end))
+    } else {
+        (Bound::Excluded(start), Bound::Excluded(end))
+    };
+
+    let now = now_millis();
+    let limit = limit.unwrap_or(usize::MAX);
+    let db = STORE.read().unwrap();
 
-    db.insert(key, val);
+    let mut result = Vec::new();
+    db.engine.range(lower, upper, rev, &mut |key, entry| {
+        if result.len() >= limit {
+            return false;
+        }
+        if !entry.is_expired(now) {
+            result.push((key.to_string(), entry.value.clone()));
+        }
+        true
+    })?;
+    Ok(result)
 }
 
-pub(crate) fn get_range(start: usize, end: usize) -> Vec<(String, usize)> {
+/// Scans all keys starting with prefix in key order
+///
+/// Parameters:
+///
+/// > prefix: &str
+/// > limit: Option<usize> (maximum number of entries returned)
+pub(crate) fn scan_prefix(prefix: &str, limit: Option<usize>) -> io::Result<Vec<(String, Value)>> {
+    let now = now_millis();
+    let limit = limit.unwrap_or(usize::MAX);
     let db = STORE.read().unwrap();
-    db.iter()
-        .filter(|(_k, &v)| v >= start && v <= end)
-        .map(|(k, &v)| (k.clone(), v))
-        .collect()
+
+    // every key with the prefix sorts right after the prefix itself, so we can stop at the first
+    // key that does not have it
+    let mut result = Vec::new();
+    db.engine.range(
+        Bound::Included(prefix),
+        Bound::Unbounded,
+        false,
+        &mut |key, entry| {
+            if result.len() >= limit || !key.starts_with(prefix) {
+                return false;
+            }
+            if !entry.is_expired(now) {
+                result.push((key.to_string(), entry.value.clone()));
+            }
+            true
+        },
+    )?;
+    Ok(result)
 }
 
-pub fn save_store<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+/// A change to one key: the entry it ends up with, None if it is removed
+pub(crate) type Change = (String, Option<Entry>);
+
+/// Starts watching a key for WATCH, returns its version -- every `watch` needs an `unwatch`
+pub(crate) fn watch(key: &str) -> u64 {
+    STORE.write().unwrap().watch(key)
+}
+
+/// Ends the watches of the keys, see `Store::unwatch`
+pub(crate) fn unwatch(watched: &[(String, u64)]) {
+    let mut store = STORE.write().unwrap();
+    for (key, _) in watched {
+        store.unwatch(key);
+    }
+}
+
+/// The version of a watched key, see `Store::version`
+pub(crate) fn version(key: &str) -> Option<u64> {
+    STORE.read().unwrap().version(key)
+}
+
+/// The entry of a key as it is in the store, expired or not
+pub(crate) fn get_entry(key: &str) -> io::Result<Option<Entry>> {
+    STORE.read().unwrap().get(key)
+}
+
+/// Works out what a write command changes, without touching the store
+///
+/// The outer error is a storage error, the inner one tells the client why the command was
+/// refused -- it is not logged then. That includes changes the storage engine could not take,
+/// see `StorageEngine::check_key`.
+///
+/// Commands whose outcome depends on the store, like INCR, get the outcome filled in. The WAL
+/// record carries it, so replaying the record sets the same value again.
+///
+/// Parameters:
+///
+/// > command: &Command
+/// > now: u64 (unix time in milliseconds, 0 while replaying the WAL since the key was alive
+/// > when the command was first executed)
+/// > get: reads the entry of a key as it is right before the command
+pub(crate) fn plan(
+    command: &mut Command,
+    now: u64,
+    get: &mut dyn FnMut(&str) -> io::Result<Option<Entry>>,
+) -> io::Result<Result<Vec<Change>, String>> {
+    let changes = match plan_command(command, now, get)? {
+        Ok(changes) => changes,
+        Err(msg) => return Ok(Err(msg)),
+    };
+
     let db = STORE.read().unwrap();
-    let serialized = serde_json::to_string(&*db).expect("Serialization Failed!");
-    fs::write(path, serialized)?;
+    for (key, entry) in &changes {
+        if entry.is_some() {
+            if let Err(msg) = db.engine.check_key(key) {
+                return Ok(Err(msg));
+            }
+        }
+    }
+    Ok(Ok(changes))
+}
+
+fn plan_command(
+    command: &mut Command,
+    now: u64,
+    get: &mut dyn FnMut(&str) -> io::Result<Option<Entry>>,
+) -> io::Result<Result<Vec<Change>, String>> {
+    let mut alive = |key: &str| -> io::Result<Option<Entry>> {
+        Ok(get(key)?.filter(|entry| !entry.is_expired(now)))
+    };
+    let not_found = || Ok(Err("Key not found in storage!".to_string()));
+
+    let change = match command {
+        Command::Store {
+            key,
+            value,
+            expires_at,
+        } => (
+            key.clone(),
+            Some(Entry {
+                value: value.clone(),
+                expires_at: *expires_at,
+            }),
+        ),
+        // like STORE this drops any expiry of the key
+        //
+        // UPDATE used to create missing keys, so a replay still does for the old WAL records --
+        // newer ones are only logged when the key was there
+        Command::Update { key, value } => match alive(key)? {
+            None if now != 0 => return not_found(),
+            _ => (
+                key.clone(),
+                Some(Entry {
+                    value: value.clone(),
+                    expires_at: None,
+                }),
+            ),
+        },
+        Command::StoreIf {
+            key,
+            value,
+            expires_at,
+            condition,
+            stored,
+        } => {
+            let holds = match *stored {
+                // already worked out when the command first ran
+                Some(holds) => holds,
+                None => {
+                    let present = alive(key)?.is_some();
+                    present == (*condition == Condition::XX)
+                }
+            };
+            *stored = Some(holds);
+            if !holds {
+                return Ok(Ok(Vec::new()));
+            }
+            (
+                key.clone(),
+                Some(Entry {
+                    value: value.clone(),
+                    expires_at: *expires_at,
+                }),
+            )
+        }
+        // drops any expiry, just like STORE
+        Command::GetSet { key, value, old } => {
+            *old = alive(key)?.map(|entry| entry.value);
+            (
+                key.clone(),
+                Some(Entry {
+                    value: value.clone(),
+                    expires_at: None,
+                }),
+            )
+        }
+        Command::Delete { key } => match alive(key)? {
+            Some(_) => (key.clone(), None),
+            None => return not_found(),
+        },
+        Command::Expire { key, expires_at } => match alive(key)? {
+            Some(mut entry) => {
+                entry.expires_at = Some(*expires_at);
+                (key.clone(), Some(entry))
+            }
+            None => return not_found(),
+        },
+        Command::Persist { key } => match alive(key)? {
+            Some(mut entry) => {
+                entry.expires_at = None;
+                (key.clone(), Some(entry))
+            }
+            None => return not_found(),
+        },
+        // keeps the expiry, the key is still the same one
+        Command::Cas {
+            key,
+            expected,
+            value,
+        } => match alive(key)? {
+            Some(mut entry) if entry.value == *expected => {
+                entry.value = value.clone();
+                (key.clone(), Some(entry))
+            }
+            Some(_) => {
+                return Ok(Err(
+                    "CAS failed: the value is not the expected one".to_string()
+                ))
+            }
+            None => return not_found(),
+        },
+        Command::Incr {
+            key,
+            by,
+            value,
+            expires_at,
+        } => {
+            let entry = alive(key)?;
+            let result = match (*value, &entry) {
+                // already worked out when the command first ran, expiry included
+                (Some(result), _) => result,
+                // a missing key counts as 0
+                (None, None) => *by,
+                (
+                    None,
+                    Some(Entry {
+                        value: Value::Int(current),
+                        ..
+                    }),
+                ) => match current.checked_add(*by) {
+                    Some(result) => result,
+                    None if *by < 0 => return Ok(Err("Decrement would underflow".to_string())),
+                    None => return Ok(Err("Increment would overflow".to_string())),
+                },
+                (None, Some(_)) => return Ok(Err("Value is not an integer".to_string())),
+            };
+            if value.is_none() {
+                *expires_at = entry.and_then(|entry| entry.expires_at);
+                *value = Some(result);
+            }
+
+            (
+                key.clone(),
+                Some(Entry {
+                    value: Value::Int(result),
+                    expires_at: *expires_at,
+                }),
+            )
+        }
+        Command::Exec { commands } => return plan_transaction(commands, now, get),
+        // like STORE this drops any expiry, a key given twice ends up with the last value
+        Command::MSet { entries } => {
+            let changes = entries
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.clone(),
+                        Some(Entry {
+                            value: value.clone(),
+                            expires_at: None,
+                        }),
+                    )
+                })
+                .collect();
+            return Ok(Ok(changes));
+        }
+        // unlike DELETE, keys that are not there are just not counted
+        Command::MDel { keys, deleted } => {
+            let mut seen = HashSet::new();
+            let mut changes: Vec<Change> = Vec::new();
+            for key in keys.iter() {
+                if seen.insert(key.as_str()) && alive(key)?.is_some() {
+                    changes.push((key.clone(), None));
+                }
+            }
+            *deleted = Some(changes.len() as u64);
+            return Ok(Ok(changes));
+        }
+        _ => return Ok(Ok(Vec::new())),
+    };
+    Ok(Ok(vec![change]))
+}
+
+/// Plans the writes of a transaction one after the other, each seeing the ones before it
+///
+/// If any of them is refused the whole transaction is, so it never half happens -- not live and
+/// not when the WAL record is replayed.
+fn plan_transaction(
+    commands: &mut [Command],
+    now: u64,
+    get: &mut dyn FnMut(&str) -> io::Result<Option<Entry>>,
+) -> io::Result<Result<Vec<Change>, String>> {
+    let mut written: HashMap<String, Option<Entry>> = HashMap::new();
+    let mut changes = Vec::new();
+
+    for (i, command) in commands.iter_mut().enumerate() {
+        let planned = plan_command(command, now, &mut |key| match written.get(key) {
+            Some(entry) => Ok(entry.clone()),
+            None => get(key),
+        })?;
+
+        match planned {
+            Ok(planned) => {
+                written.extend(planned.iter().cloned());
+                changes.extend(planned);
+            }
+            Err(msg) => {
+                return Ok(Err(format!(
+                    "Transaction aborted at command {}: {}",
+                    i + 1,
+                    msg
+                )))
+            }
+        }
+    }
+    Ok(Ok(changes))
+}
 
-    // clear the WAL once you make a snapshot!
-    let _ = logger::clear_wal();
+/// Applies the changes of the WAL records up to lsn to the store, all under one lock
+pub(crate) fn apply_changes<I: IntoIterator<Item = Change>>(
+    changes: I,
+    lsn: u64,
+) -> io::Result<()> {
+    let mut db = STORE.write().unwrap();
 
+    for (key, entry) in changes {
+        match entry {
+            Some(entry) => db.insert(key, entry)?,
+            None => db.remove(&key)?,
+        };
+    }
+    db.engine.set_lsn(lsn)?;
+    db.applied_lsn = lsn;
     Ok(())
 }
 
-pub fn load_store<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
-    if !path.as_ref().exists() {
-        return Ok(());
+/// Tells the store it is caught up with the WAL up to lsn, for after a recovery
+pub(crate) fn set_applied_lsn(lsn: u64) -> io::Result<()> {
+    let mut db = STORE.write().unwrap();
+    db.engine.set_lsn(lsn)?;
+    db.applied_lsn = lsn;
+    Ok(())
+}
+
+/// Gets everything the store holds onto disk, for a disk engine, see `StorageEngine::sync`
+pub(crate) fn sync_engine() -> io::Result<()> {
+    STORE.read().unwrap().engine.sync()
+}
+
+/// Remaining time to live of a key in milliseconds
+///
+/// None if the key does not exist, Some(None) if the key never expires
+pub(crate) fn ttl(key: String) -> io::Result<Option<Option<u64>>> {
+    let now = now_millis();
+    let db = STORE.read().unwrap();
+
+    Ok(db
+        .get(&key)?
+        .filter(|entry| !entry.is_expired(now))
+        .map(|entry| entry.expires_at.map(|deadline| deadline - now)))
+}
+
+// how many expired keys are dropped under one write lock, writes wait for that long
+const PURGE_BATCH: usize = 256;
+
+/// Drops every expired key from the store
+///
+/// returns the number of keys removed
+pub(crate) fn purge_expired() -> io::Result<usize> {
+    let now = now_millis();
+
+    let mut removed = 0;
+    loop {
+        let mut db = STORE.write().unwrap();
+        let expired = db.expired_keys(now, PURGE_BATCH);
+        for key in &expired {
+            db.remove(key)?;
+        }
+        removed += expired.len();
+        if expired.len() < PURGE_BATCH {
+            return Ok(removed);
+        }
     }
+}
 
-    let data = fs::read_to_string(path)?;
-    if data.trim().is_empty() {
-        eprintln!("No snapshot to load");
-        return Ok(());
+/// Where the last snapshot left off, the snapshot policy counts from here
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SavePoint {
+    pub(crate) at: Instant,
+    pub(crate) lsn: u64,
+    /// logger::bytes_written() at the time
+    pub(crate) wal_bytes: u64,
+}
+
+/// the last snapshot, also one at a time -- the scheduled one and an admin one could otherwise
+/// collide
+static SAVED: Lazy<Mutex<SavePoint>> = Lazy::new(|| {
+    Mutex::new(SavePoint {
+        at: Instant::now(),
+        lsn: 0,
+        wal_bytes: 0,
+    })
+});
+
+pub(crate) fn last_saved() -> SavePoint {
+    *SAVED.lock().unwrap()
+}
+
+/// The LSN of the last WAL record applied to the store
+pub(crate) fn applied_lsn() -> u64 {
+    STORE.read().unwrap().applied_lsn
+}
+
+/// Saves a snapshot of the store into dir, then drops what it makes redundant
+///
+/// Only the newest CONFIG.snapshot_keep snapshots stay around, and the WAL is trimmed down to
+/// what the oldest of them and the storage engine still need.
+///
+/// returns the last LSN the snapshot holds
+pub fn save_store<P: AsRef<Path>>(dir: P) -> std::io::Result<u64> {
+    let dir = dir.as_ref();
+    let mut saved = SAVED.lock().unwrap();
+
+    // the lock is only held to take the point in time view and what the engine has to write,
+    // writes go on while the engine syncs and the snapshot is written out
+    let (mut view, checkpoint, point) = {
+        let db = STORE.read().unwrap();
+        // writes only reach the store once they are logged, in LSN order, so the snapshot holds
+        // exactly the records up to the last one applied
+        let view = db.engine.snapshot()?;
+        let checkpoint = db.engine.checkpoint()?;
+        let point = SavePoint {
+            at: Instant::now(),
+            lsn: db.applied_lsn,
+            wal_bytes: logger::bytes_written(),
+        };
+        (view, checkpoint, point)
+    };
+    let lsn = point.lsn;
+
+    checkpoint()?;
+    let engine_lsn = STORE.read().unwrap().engine.persisted_lsn();
+
+    let path = snapshot::write_snapshot(
+        dir,
+        &mut view,
+        lsn,
+        CONFIG.snapshot_format,
+        CONFIG.compression,
+    )?;
+    eprintln!("Saved snapshot {:?} up to LSN {}", path, lsn);
+
+    // only now that the snapshot is on disk can the WAL it covers go
+    let oldest_lsn = snapshot::prune_snapshots(dir, CONFIG.snapshot_keep)?;
+    // a disk engine replays the WAL after its own LSN on a restart
+    logger::remove_covered_segments(engine_lsn.map_or(oldest_lsn, |lsn| lsn.min(oldest_lsn)))?;
+
+    *saved = point;
+    Ok(lsn)
+}
+
+/// Loads the newest intact snapshot from dir into the store
+///
+/// A disk engine that holds the WAL up to some LSN already carries on from there instead, the
+/// snapshots are only needed for a point in time recovery then.
+///
+/// returns the last WAL LSN the store holds, 0 if there was nothing to load
+pub fn load_store<P: AsRef<Path>>(dir: P) -> std::io::Result<u64> {
+    let engine_lsn = STORE.read().unwrap().engine.persisted_lsn();
+    if let Some(lsn) = engine_lsn.filter(|lsn| *lsn > 0) {
+        eprintln!("The storage engine holds the WAL up to LSN {}", lsn);
+        set_applied_lsn(lsn)?;
+        SAVED.lock().unwrap().lsn = lsn;
+        return Ok(lsn);
     }
 
-    let db: BTreeMap<String, usize> =
-        serde_json::from_str(&data).expect("Derserialization into BTreeMap Failed!!");
+    let loaded = snapshot::read_latest(dir.as_ref(), &mut |_, entries| restore_entries(entries))?;
+    let Some(header) = loaded else {
+        eprintln!("No snapshot to load");
+        // a disk engine without an LSN may still hold part of the WAL, and replaying a
+        // record twice is not always harmless -- start it empty, the whole WAL is still there
+        restore_entries(&mut std::iter::empty())?;
+        finish_restore(0)?;
+        return Ok(0);
+    };
 
-    let mut store = STORE.write().unwrap();
-    *store = db;
+    finish_restore(header.last_lsn)?;
+    SAVED.lock().unwrap().lsn = header.last_lsn;
     println!("Snapshot Loaded!");
-    Ok(())
+    Ok(header.last_lsn)
+}
+
+/// Replaces everything in the store with the entries of a snapshot
+///
+/// The store holds no LSN until `finish_restore` -- the snapshot is only known to be intact once
+/// all of it went by, and a disk engine must not carry on from a damaged one after a restart.
+pub(crate) fn restore_entries(
+    entries: &mut dyn Iterator<Item = (String, Entry)>,
+) -> io::Result<()> {
+    // not in the middle of a save, which may still be reading or writing the engine's files
+    let _saved = SAVED.lock().unwrap();
+    STORE.write().unwrap().engine.restore(entries)
+}
+
+/// Ends a restore from a snapshot that goes up to lsn
+///
+/// A disk engine is synced right away, so a restart carries on from lsn without the snapshot.
+pub(crate) fn finish_restore(lsn: u64) -> io::Result<()> {
+    let _saved = SAVED.lock().unwrap();
+    let mut store = STORE.write().unwrap();
+    store.engine.set_lsn(lsn)?;
+    store.engine.sync()?;
+    store.applied_lsn = lsn;
+    store.reindex()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// plans a command against a single entry, like the writer and the replay do
+    fn plan_on(
+        command: &mut Command,
+        now: u64,
+        entry: &Option<Entry>,
+    ) -> Result<Vec<Change>, String> {
+        plan(command, now, &mut |_| Ok(entry.clone())).unwrap()
+    }
+
+    #[test]
+    fn the_deadline_index_follows_every_change() {
+        let mut store = Store::new(Box::<MemoryEngine>::default()).unwrap();
+        let entry = |expires_at| Entry {
+            value: Value::Int(1),
+            expires_at,
+        };
+        store.insert("a".to_string(), entry(Some(1000))).unwrap();
+        store.insert("b".to_string(), entry(Some(2000))).unwrap();
+        store.insert("c".to_string(), entry(Some(500))).unwrap();
+        store.insert("d".to_string(), entry(Some(100))).unwrap();
+        // stored again without an expiry, for longer, and removed
+        store.insert("a".to_string(), entry(None)).unwrap();
+        store.insert("c".to_string(), entry(Some(3000))).unwrap();
+        store.remove("d").unwrap();
+
+        assert!(store.expired_keys(1999, 10).is_empty());
+        assert_eq!(store.expired_keys(5000, 10), vec!["b", "c"]);
+        assert_eq!(store.expired_keys(5000, 1), vec!["b"]);
+
+        // and is built again from what the engine holds
+        store.expiring.clear();
+        store.reindex().unwrap();
+        assert_eq!(store.expired_keys(5000, 10), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn only_watched_keys_have_versions() {
+        let mut store = Store::new(Box::<MemoryEngine>::default()).unwrap();
+        let entry = Entry {
+            value: Value::Int(1),
+            expires_at: None,
+        };
+        for i in 0..100 {
+            store.insert(format!("key{}", i), entry.clone()).unwrap();
+        }
+        assert!(store.watched.is_empty());
+
+        // two sessions watch the same key, it changes, then it is removed
+        let seen = store.watch("key1");
+        assert_eq!(store.watch("key1"), seen);
+        store.insert("key2".to_string(), entry.clone()).unwrap();
+        assert_eq!(store.version("key1"), Some(seen));
+        store.insert("key1".to_string(), entry.clone()).unwrap();
+        let written = store.version("key1").unwrap();
+        assert_ne!(written, seen);
+        store.remove("key1").unwrap();
+        assert_ne!(store.version("key1"), Some(written));
+
+        // a key that is not there yet can be watched as well
+        let missing = store.watch("new");
+        store.insert("new".to_string(), entry).unwrap();
+        assert_ne!(store.version("new"), Some(missing));
+
+        store.unwatch("key1");
+        assert!(store.version("key1").is_some());
+        store.unwatch("key1");
+        store.unwatch("new");
+        assert!(store.watched.is_empty());
+    }
+
+    #[test]
+    fn incr_of_an_expired_key_replays_the_same() {
+        let expired = Some(Entry {
+            value: Value::Int(5),
+            expires_at: Some(1000),
+        });
+        let mut command = Command::Incr {
+            key: "hits".to_string(),
+            by: 1,
+            value: None,
+            expires_at: None,
+        };
+
+        // live, the key is gone, so it starts over from 0 without the old expiry
+        let live = plan_on(&mut command, 2000, &expired).unwrap();
+        assert_eq!(
+            live,
+            vec![(
+                "hits".to_string(),
+                Some(Entry {
+                    value: Value::Int(1),
+                    expires_at: None,
+                })
+            )]
+        );
+
+        // the replay sees the old entry as alive, the logged outcome has to win
+        let logged = bincode::serialize(&command).unwrap();
+        let mut replayed: Command = bincode::deserialize(&logged).unwrap();
+        assert_eq!(plan_on(&mut replayed, 0, &expired).unwrap(), live);
+    }
+
+    #[test]
+    fn incr_keeps_the_expiry_of_a_live_key() {
+        let entry = Some(Entry {
+            value: Value::Int(5),
+            expires_at: Some(5000),
+        });
+        let mut command = Command::Incr {
+            key: "hits".to_string(),
+            by: -2,
+            value: None,
+            expires_at: None,
+        };
+
+        let live = plan_on(&mut command, 2000, &entry).unwrap();
+        assert_eq!(
+            live[0].1,
+            Some(Entry {
+                value: Value::Int(3),
+                expires_at: Some(5000),
+            })
+        );
+        assert_eq!(plan_on(&mut command, 0, &entry).unwrap(), live);
+    }
 }
@@ -0,0 +1,370 @@
+// ROC/rocs/src/tool.rs
+
+// roc-tool: looks at the files rocs leaves behind without starting the server
+//
+//  roc-tool wal dump [logs dir]                 -- every WAL record, readable
+//  roc-tool wal repair [logs dir]               -- cut a torn write off the end of the WAL
+//  roc-tool snapshot verify <file>              -- check the header and checksum
+//  roc-tool snapshot dump <file>                -- header and all the entries
+//  roc-tool diff <old snapshot> <new snapshot>  -- what changed between two snapshots
+//  roc-tool check [snapshot dir] [logs dir]     -- can a restart recover from these files?
+//
+// The directories default to the ones rocs uses, so run it from where rocs runs. Encrypted files
+// need the same ROC_KEY_FILE rocs has, and check looks at the engine in ROC_ENGINE and
+// ROC_DATA_DIR like a restart would. Exits with 1 when it finds a problem and 2 on bad
+// arguments.
+
+use crate::config::CONFIG;
+use crate::engine;
+use crate::logger::{self, SegmentScan, WalRecord};
+use crate::snapshot::{self, SnapshotHeader};
+use crate::store::Entry;
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const USAGE: &str = "usage:
+  roc-tool wal dump [logs dir]
+  roc-tool wal repair [logs dir]
+  roc-tool snapshot verify <file>
+  roc-tool snapshot dump <file>
+  roc-tool diff <old snapshot> <new snapshot>
+  roc-tool check [snapshot dir] [logs dir]";
+
+/// Runs roc-tool with its arguments, returns the exit code
+pub fn run(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["wal", "dump", rest @ ..] if rest.len() <= 1 => wal_dump(logs_dir(rest.first())),
+        ["wal", "repair", rest @ ..] if rest.len() <= 1 => wal_repair(logs_dir(rest.first())),
+        ["snapshot", "verify", file] => snapshot_verify(Path::new(file)),
+        ["snapshot", "dump", file] => snapshot_dump(Path::new(file)),
+        ["diff", old, new] => diff(Path::new(old), Path::new(new)),
+        ["check", rest @ ..] if rest.len() <= 2 => check(
+            Path::new(rest.first().copied().unwrap_or(snapshot::SNAPSHOT_DIR)),
+            logs_dir(rest.get(1)),
+        ),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            1
+        }
+    }
+}
+
+fn logs_dir<'a>(arg: Option<&&'a str>) -> &'a Path {
+    Path::new(arg.copied().unwrap_or(logger::WAL_DIR))
+}
+
+/// Reads every segment in dir without touching them, oldest first
+fn scan_wal(dir: &Path) -> Result<Vec<(String, SegmentScan)>, String> {
+    let segments = logger::list_segments(dir).map_err(|e| e.to_string())?;
+
+    let mut scans = Vec::new();
+    let mut last_lsn = 0;
+    for (i, (first_lsn, path)) in segments.iter().enumerate() {
+        let newest = i + 1 == segments.len();
+        let scan = logger::scan_segment(path, *first_lsn, newest, &mut last_lsn)
+            .map_err(|e| e.to_string())?;
+        scans.push((path.display().to_string(), scan));
+    }
+    Ok(scans)
+}
+
+fn wal_dump(dir: &Path) -> Result<(), String> {
+    let segments = logger::list_segments(dir).map_err(|e| e.to_string())?;
+    if segments.is_empty() {
+        println!("no WAL segments in {}", dir.display());
+        return Ok(());
+    }
+
+    // print as we go, so that everything up to a damaged record still shows
+    let mut last_lsn = 0;
+    for (i, (first_lsn, path)) in segments.iter().enumerate() {
+        let newest = i + 1 == segments.len();
+        let scan = logger::scan_segment(path, *first_lsn, newest, &mut last_lsn)
+            .map_err(|e| e.to_string())?;
+
+        println!(
+            "== {} (version {}, compression {}, {}{} records)",
+            path.display(),
+            scan.version,
+            setting(scan.compression),
+            if scan.encrypted { "encrypted, " } else { "" },
+            scan.records.len()
+        );
+        for record in &scan.records {
+            print_record(record);
+        }
+        if let Some(msg) = &scan.torn {
+            return Err(format!(
+                "torn write at offset {} of {}: {} -- `roc-tool wal repair` cuts it off",
+                scan.valid_len,
+                path.display(),
+                msg
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn print_record(record: &WalRecord) {
+    let command = serde_json::to_string(&record.command).unwrap_or_default();
+    let time = if record.timestamp == 0 {
+        "-".to_string()
+    } else {
+        format_time(record.timestamp)
+    };
+    println!("{:>10}  {}  {}", record.lsn, time, command);
+}
+
+fn wal_repair(dir: &Path) -> Result<(), String> {
+    let segments = logger::list_segments(dir).map_err(|e| e.to_string())?;
+    let scans = scan_wal(dir).map_err(|msg| {
+        format!(
+            "{}\nonly a torn write at the very end can be repaired, this needs a look by hand",
+            msg
+        )
+    })?;
+
+    for ((_, scan), (_, path)) in scans.iter().zip(&segments) {
+        if let Some(msg) = &scan.torn {
+            logger::repair_segment(path, scan).map_err(|e| e.to_string())?;
+            println!(
+                "cut {} off at offset {} ({})",
+                path.display(),
+                scan.valid_len,
+                msg
+            );
+            return Ok(());
+        }
+    }
+
+    println!("nothing to repair");
+    Ok(())
+}
+
+fn snapshot_verify(path: &Path) -> Result<(), String> {
+    let header = snapshot::verify_snapshot(path)
+        .map_err(|msg| format!("{}: damaged snapshot: {}", path.display(), msg))?;
+
+    println!("{}: ok", path.display());
+    print_header(&header);
+    Ok(())
+}
+
+fn snapshot_dump(path: &Path) -> Result<(), String> {
+    let (header, entries) = snapshot::read_snapshot(path)
+        .map_err(|msg| format!("{}: damaged snapshot: {}", path.display(), msg))?;
+
+    print_header(&header);
+    for (key, entry) in &entries {
+        println!("{}", show_entry(key, entry));
+    }
+    Ok(())
+}
+
+fn print_header(header: &SnapshotHeader) {
+    println!("  version    {}", header.version);
+    println!("  format     {}", setting(header.format));
+    println!("  compressed {}", setting(header.compression));
+    println!("  encrypted  {}", header.encrypted);
+    println!("  created at {}", format_time(header.created_at));
+    println!("  entries    {}", header.entries);
+    println!("  checksum   {:08x}", header.checksum);
+    println!("  last LSN   {}", header.last_lsn);
+}
+
+fn diff(old: &Path, new: &Path) -> Result<(), String> {
+    let read = |path: &Path| {
+        snapshot::read_snapshot(path)
+            .map(|(_, entries)| entries)
+            .map_err(|msg| format!("{}: damaged snapshot: {}", path.display(), msg))
+    };
+    let (old, new): (BTreeMap<String, Entry>, BTreeMap<String, Entry>) = (read(old)?, read(new)?);
+
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for (key, entry) in &old {
+        match new.get(key) {
+            None => {
+                println!("- {}", show_entry(key, entry));
+                removed += 1;
+            }
+            Some(now) if now != entry => {
+                println!("~ {}", show_entry(key, entry));
+                println!("  -> {}", show_entry(key, now));
+                changed += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, entry) in &new {
+        if !old.contains_key(key) {
+            println!("+ {}", show_entry(key, entry));
+            added += 1;
+        }
+    }
+
+    println!("{} added, {} removed, {} changed", added, removed, changed);
+    Ok(())
+}
+
+fn check(snapshot_dir: &Path, logs_dir: &Path) -> Result<(), String> {
+    let mut problems = Vec::new();
+
+    for (_, path) in snapshot::list_snapshots(snapshot_dir).map_err(|e| e.to_string())? {
+        match snapshot::verify_snapshot(&path) {
+            Ok(header) => println!(
+                "snapshot {}: ok, {} entries up to LSN {}",
+                path.display(),
+                header.entries,
+                header.last_lsn
+            ),
+            Err(msg) => problems.push(format!("snapshot {}: {}", path.display(), msg)),
+        }
+    }
+
+    // where a restart carries on from: a disk engine that holds part of the WAL, like
+    // `store::load_store` does, otherwise the snapshot it would load
+    let engine_lsn = engine::peek_lsn(&CONFIG)
+        .map_err(|e| format!("a restart would fail: {}", e))?
+        .filter(|lsn| *lsn > 0);
+    let start_lsn = match engine_lsn {
+        Some(lsn) => {
+            println!(
+                "the {} engine in {} holds the WAL up to LSN {}",
+                setting(CONFIG.engine),
+                CONFIG.data_dir.display(),
+                lsn
+            );
+            lsn
+        }
+        None => {
+            let skip: snapshot::Load = &mut |_, entries| {
+                entries.for_each(drop);
+                Ok(())
+            };
+            match snapshot::read_latest(snapshot_dir, skip) {
+                Ok(Some(header)) => header.last_lsn,
+                Ok(None) => {
+                    println!("no snapshot, a restart starts from an empty store");
+                    0
+                }
+                Err(e) => return Err(format!("a restart would fail: {}", e)),
+            }
+        }
+    };
+
+    let scans = scan_wal(logs_dir).map_err(|msg| format!("a restart would fail: {}", msg))?;
+    let records: Vec<&WalRecord> = scans.iter().flat_map(|(_, scan)| &scan.records).collect();
+    match (records.first(), records.last()) {
+        (Some(first), Some(last)) => println!(
+            "WAL: {} segments, LSN {} to {}",
+            scans.len(),
+            first.lsn,
+            last.lsn
+        ),
+        _ => println!("WAL: {} segments, no records", scans.len()),
+    }
+    for (path, scan) in &scans {
+        if let Some(msg) = &scan.torn {
+            println!(
+                "WAL: torn write at offset {} of {} ({}), a restart cuts it off",
+                scan.valid_len, path, msg
+            );
+        }
+    }
+
+    let from = match engine_lsn {
+        Some(_) => "the engine",
+        None => "the snapshot",
+    };
+    let missing: Vec<_> = records.iter().filter(|r| r.lsn > start_lsn).collect();
+    match missing.first() {
+        Some(first) if first.lsn != start_lsn + 1 => {
+            return Err(format!(
+                "a restart would fail: {} ends at LSN {} but the WAL only goes back to LSN {}",
+                from, start_lsn, first.lsn
+            ))
+        }
+        _ => println!(
+            "a restart carries on from {} at LSN {} and replays {} records",
+            from,
+            start_lsn,
+            missing.len()
+        ),
+    }
+
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+    Ok(())
+}
+
+fn show_entry(key: &str, entry: &Entry) -> String {
+    match entry.expires_at {
+        Some(deadline) => format!(
+            "{} = {} (expires {})",
+            key,
+            show_value(&entry.value),
+            format_time(deadline)
+        ),
+        None => format!("{} = {}", key, show_value(&entry.value)),
+    }
+}
+
+/// a setting spelled the way the ROC_* variables take it
+fn setting<T: std::fmt::Debug>(value: T) -> String {
+    format!("{:?}", value).to_lowercase()
+}
+
+/// values the way rocd takes them
+fn show_value(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::Float(f) => format!("{:?}", f),
+        Value::Str(s) => format!("{:?}", s),
+        Value::Bytes(bytes) => {
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}", hex)
+        }
+    }
+}
+
+/// unix milliseconds as "YYYY-MM-DD hh:mm:ss.mmm UTC"
+fn format_time(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+
+    // days since 1970-01-01 to a civil date, see Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03} UTC",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        ms % 1000
+    )
+}
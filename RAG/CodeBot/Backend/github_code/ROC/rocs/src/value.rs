@@ -0,0 +1,162 @@
+// ROC/rocs/src/value.rs
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// A value held in the store
+///
+/// This is what flows through `Command`, the store, the WAL and the snapshots.
+/// It is serialized externally tagged (eg. `{"Int": 12}`), so a value comes back
+/// with the same type it was stored with after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// position of the type when comparing values of different types
+    ///
+    /// Null < Bool < Int/Float < Str < Bytes
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Int(_) | Value::Float(_) => 2,
+            Value::Str(_) => 3,
+            Value::Bytes(_) => 4,
+        }
+    }
+}
+
+// we need a total order on values for RANGE queries .. ints and floats are compared by their
+// numeric value so that `GET BETWEEN 1 10` also picks up 2.5
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+            // on a numeric tie the int goes first, so that Int(1) and Float(1.0) stay different
+            (Value::Int(a), Value::Float(b)) => (*a as f64).total_cmp(b).then(Ordering::Less),
+            (Value::Float(a), Value::Int(b)) => a.total_cmp(&(*b as f64)).then(Ordering::Greater),
+            (Value::Str(a), Value::Str(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+/// Converts a value from before typed values, when every value was an unsigned number
+pub(crate) fn from_legacy(n: u64) -> Result<Value, String> {
+    i64::try_from(n)
+        .map(Value::Int)
+        .map_err(|_| format!("Legacy value {} does not fit into a signed 64 bit value", n))
+}
+
+/// Converts a value received in a JSON request into a store value
+///
+/// null, bool, string map onto themselves, numbers become Int if they fit into an i64 and Float
+/// otherwise, an array of numbers in 0..=255 becomes Bytes
+///
+/// Clients from before typed values sent their numbers as strings, so a string that is an
+/// integer written the plain way ("12", "-3", not "012" or "+3") becomes Int like they expect.
+/// A value in the tagged form the replies use, like {"Str": "12"}, is taken as it is -- that is
+/// how a string of digits is stored as a string.
+///
+/// Parameters:
+///
+/// > json: &serde_json::Value
+pub(crate) fn from_json(json: &serde_json::Value) -> Result<Value, String> {
+    match json {
+        serde_json::Value::Null => Ok(Value::Null),
+        serde_json::Value::Bool(b) => Ok(Value::Bool(*b)),
+        serde_json::Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Ok(Value::Int(i))
+            } else if n.is_u64() {
+                Err(format!(
+                    "Integer {} does not fit into a signed 64 bit value",
+                    n
+                ))
+            } else {
+                n.as_f64()
+                    .map(Value::Float)
+                    .ok_or_else(|| format!("Invalid number {}", n))
+            }
+        }
+        serde_json::Value::String(s) => match s.parse::<i64>() {
+            Ok(i) if i.to_string() == *s => Ok(Value::Int(i)),
+            _ => Ok(Value::Str(s.clone())),
+        },
+        serde_json::Value::Array(items) => items
+            .iter()
+            .map(|item| {
+                item.as_u64()
+                    .and_then(|b| u8::try_from(b).ok())
+                    .ok_or_else(|| {
+                        "Bytes must be given as an array of numbers in 0..=255".to_string()
+                    })
+            })
+            .collect::<Result<Vec<u8>, String>>()
+            .map(Value::Bytes),
+        serde_json::Value::Object(_) => serde_json::from_value(json.clone()).map_err(|_| {
+            "Objects are not supported as values, except typed ones like {\"Str\": \"12\"}"
+                .to_string()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn strings_of_old_clients_that_are_integers_become_ints() {
+        assert_eq!(from_json(&json!("12")).unwrap(), Value::Int(12));
+        assert_eq!(from_json(&json!("-3")).unwrap(), Value::Int(-3));
+        for s in [
+            "012",
+            "+3",
+            "1.5",
+            "12 ",
+            "",
+            "99999999999999999999",
+            "ashu",
+        ] {
+            assert_eq!(
+                from_json(&json!(s)).unwrap(),
+                Value::Str(s.to_string()),
+                "{:?}",
+                s
+            );
+        }
+
+        // unless they say they are strings
+        assert_eq!(
+            from_json(&json!({"Str": "12"})).unwrap(),
+            Value::Str("12".to_string())
+        );
+        assert_eq!(from_json(&json!({"Int": 7})).unwrap(), Value::Int(7));
+        assert!(from_json(&json!({"name": "ashu"})).is_err());
+    }
+}
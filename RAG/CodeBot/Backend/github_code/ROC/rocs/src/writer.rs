@@ -0,0 +1,167 @@
+// ROC/rocs/src/writer.rs
+
+use crate::command::Command;
+use crate::logger;
+use crate::store::{self, Change, Entry};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+// The write path: client threads never change the store themselves, they hand their write to the
+// writer thread and wait for the answer. The writer takes everything that queued up in the
+// meantime and
+//
+//  1. checks each write against the store, as left by the writes before it in the batch
+//  2. logs the ones that go through and change something to the WAL, all in one go (group commit)
+//  3. only then applies them to the store, in LSN order, and answers the clients
+//
+// So a write is visible once it is in the WAL and not before, a write that could not be logged
+// is never applied, and the store always holds exactly the log up to some LSN. If a logged batch
+// can not be applied the server stops, the lock file makes the next start replay it.
+
+/// A write waiting for the writer thread, done hears back with the reply for the client
+struct PendingWrite {
+    command: Command,
+    /// keys with the version the client saw, the write is refused if any of them changed
+    watched: Vec<(String, u64)>,
+    done: Sender<Result<Command, String>>,
+}
+
+/// the queue into the writer thread, which is started with the first write
+static QUEUE: Lazy<Sender<PendingWrite>> = Lazy::new(|| {
+    let (queue, pending) = mpsc::channel();
+    thread::spawn(move || write_batches(pending));
+    queue
+});
+
+/// Logs and applies a write command, returns the reply for the client
+///
+/// An Ok reply means the write is in the WAL (synced, with fsync=always) and in the store.
+pub(crate) fn submit(command: Command) -> Result<Command, String> {
+    submit_watched(command, Vec::new())
+}
+
+/// Like `submit`, but only if none of the watched keys changed from the given version
+pub(crate) fn submit_watched(
+    command: Command,
+    watched: Vec<(String, u64)>,
+) -> Result<Command, String> {
+    logger::check_size(&command)?;
+
+    let (done, reply) = mpsc::channel();
+    let gone = || "Storage error: the writer thread is gone".to_string();
+
+    QUEUE
+        .send(PendingWrite {
+            command,
+            watched,
+            done,
+        })
+        .map_err(|_| gone())?;
+    reply.recv().map_err(|_| gone())?
+}
+
+fn write_batches(pending: Receiver<PendingWrite>) {
+    while let Ok(first) = pending.recv() {
+        let mut batch = vec![first];
+        batch.extend(pending.try_iter());
+        write_batch(batch);
+    }
+}
+
+fn write_batch(batch: Vec<PendingWrite>) {
+    let now = store::now_millis();
+    // the entries as the writes so far in this batch left them, they are not in the store yet
+    let mut written: HashMap<String, Option<Entry>> = HashMap::new();
+
+    let mut accepted: Vec<(PendingWrite, Vec<Change>)> = Vec::new();
+    for mut write in batch {
+        if watched_changed(&write.watched, &written) {
+            let _ = write
+                .done
+                .send(Err("Transaction aborted: a watched key changed".to_string()));
+            continue;
+        }
+
+        let planned = store::plan(&mut write.command, now, &mut |key| match written.get(key) {
+            Some(entry) => Ok(entry.clone()),
+            None => store::get_entry(key),
+        });
+
+        match planned {
+            Ok(Ok(changes)) => {
+                written.extend(changes.iter().cloned());
+                accepted.push((write, changes));
+            }
+            Ok(Err(msg)) => {
+                let _ = write.done.send(Err(msg));
+            }
+            Err(e) => {
+                let _ = write.done.send(Err(storage_error(&e)));
+            }
+        }
+    }
+    if accepted.is_empty() {
+        return;
+    }
+
+    // a write that changes nothing, like a STORE NX of a key that is there, needs no record
+    let commands: Vec<&Command> = accepted
+        .iter()
+        .filter(|(_, changes)| !changes.is_empty())
+        .map(|(write, _)| &write.command)
+        .collect();
+    let result = if commands.is_empty() {
+        Ok(())
+    } else {
+        match logger::append(&commands) {
+            Ok(lsn) => {
+                let changes = accepted
+                    .iter_mut()
+                    .flat_map(|(_, changes)| changes.drain(..));
+                // the writes are in the WAL and come back on a restart, so they can not be
+                // reported as failed -- and going on with the store behind the log would answer
+                // later writes from the wrong state. Stop here and let the replay catch up.
+                if let Err(e) = store::apply_changes(changes, lsn) {
+                    eprintln!(
+                        "Failed to apply writes up to LSN {} after logging them: {}",
+                        lsn, e
+                    );
+                    eprintln!("Stopping, a restart replays them from the WAL");
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to write a batch of {} records to the WAL: {}",
+                    commands.len(),
+                    e
+                );
+                Err(e)
+            }
+        }
+    };
+
+    for (write, _) in accepted {
+        let reply = match &result {
+            Ok(()) => Ok(write.command),
+            Err(e) => Err(storage_error(e)),
+        };
+        // the client may have hung up in the meantime, nothing to tell then
+        let _ = write.done.send(reply);
+    }
+}
+
+/// Whether a watched key changed, in the store or by a write earlier in the batch
+fn watched_changed(watched: &[(String, u64)], written: &HashMap<String, Option<Entry>>) -> bool {
+    watched
+        .iter()
+        .any(|(key, version)| written.contains_key(key) || store::version(key) != Some(*version))
+}
+
+fn storage_error(e: &io::Error) -> String {
+    format!("Storage error: {}", e)
+}
@@ -0,0 +1,150 @@
+// ROC/rocs/tests/common/mod.rs
+
+// Runs the rocs binary for the tests in this directory. Every server gets a directory of its
+// own: it runs in <dir>/run (logs/ and data/ end up there) and keeps its snapshots in
+// <dir>/snaps, next to it.
+
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A fresh directory for one test, the name tells tests and test runs apart
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rocs-it-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("run")).unwrap();
+    fs::create_dir_all(dir.join("snaps")).unwrap();
+    dir
+}
+
+/// Runs a binary of this package in the run directory of dir and waits for it
+pub fn run(bin: &str, dir: &Path, env: &[(&str, &str)], args: &[&str]) -> Output {
+    Command::new(bin)
+        .args(args)
+        .current_dir(dir.join("run"))
+        .envs(env.iter().copied())
+        .output()
+        .unwrap()
+}
+
+pub struct Server {
+    child: Child,
+    admin: ChildStdin,
+    addr: String,
+    log: PathBuf,
+}
+
+impl Server {
+    /// Starts rocs in dir with the extra environment and waits until it takes connections
+    pub fn start(dir: &Path, env: &[(&str, &str)]) -> Server {
+        // let the OS pick a port that is free right now
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let log = dir.join("server.log");
+        let stderr = File::options()
+            .create(true)
+            .append(true)
+            .open(&log)
+            .unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rocs"))
+            .current_dir(dir.join("run"))
+            .env("ROC_ADDR", &addr)
+            .envs(env.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(stderr)
+            .spawn()
+            .unwrap();
+        let admin = child.stdin.take().unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(&addr).is_err() {
+            if let Some(status) = child.try_wait().unwrap() {
+                panic!(
+                    "rocs exited with {} before taking connections:\n{}",
+                    status,
+                    fs::read_to_string(&log).unwrap_or_default()
+                );
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(30),
+                "rocs did not start"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        Server {
+            child,
+            admin,
+            addr,
+            log,
+        }
+    }
+
+    /// A new connection to the server
+    pub fn client(&self) -> Client {
+        let stream = TcpStream::connect(&self.addr).unwrap();
+        Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+        }
+    }
+
+    /// SHUTDOWN on the admin interface, which takes a snapshot on the way out
+    pub fn shutdown(mut self) {
+        self.admin.write_all(b"SHUTDOWN\n").unwrap();
+        let status = self.child.wait().unwrap();
+        assert!(status.success(), "rocs exited with {}", status);
+    }
+
+    /// Stops the server the hard way, like a crash
+    pub fn kill(mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+    }
+
+    /// What the server printed so far
+    pub fn log(&self) -> String {
+        fs::read_to_string(&self.log).unwrap_or_default()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+
+impl Client {
+    /// Sends one request and returns the reply
+    pub fn request(&mut self, request: Value) -> Value {
+        self.stream
+            .write_all(format!("{}\n", request).as_bytes())
+            .unwrap();
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap_or_else(|_| panic!("bad reply {:?}", line))
+    }
+}
+
+/// The error message of a reply, None if it is not an error
+pub fn error(reply: &Value) -> Option<&str> {
+    reply["ERR"]["msg"].as_str()
+}
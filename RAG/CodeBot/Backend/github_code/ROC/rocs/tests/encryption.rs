@@ -0,0 +1,88 @@
+// ROC/rocs/tests/encryption.rs
+
+mod common;
+
+use common::{run, test_dir, Server};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+/// Whether any file under dir holds needle
+fn leaks(dir: &Path, needle: &[u8]) -> bool {
+    fs::read_dir(dir).unwrap().any(|file| {
+        let path = file.unwrap().path();
+        if path.is_dir() {
+            return leaks(&path, needle);
+        }
+        let data = fs::read(&path).unwrap();
+        data.windows(needle.len()).any(|w| w == needle)
+    })
+}
+
+#[test]
+fn nothing_reaches_the_disk_in_plaintext_and_only_the_key_reads_it_back() {
+    let dir = test_dir("encryption");
+    let key = dir.join("key");
+    let wrong_key = dir.join("wrong-key");
+    fs::write(&key, [7u8; 32]).unwrap();
+    fs::write(&wrong_key, [8u8; 32]).unwrap();
+    let key_env = [("ROC_KEY_FILE", key.to_str().unwrap())];
+
+    let server = Server::start(&dir, &key_env);
+    let mut client = server.client();
+    client.request(json!({"command": "STORE", "key": "in-snapshot", "value": "plaintext-one"}));
+    client.request(json!({"command": "SNAPSHOT"}));
+    client.request(json!({"command": "STORE", "key": "in-wal", "value": "plaintext-two"}));
+    server.kill();
+
+    for needle in ["plaintext-one", "plaintext-two", "in-snapshot", "in-wal"] {
+        assert!(!leaks(&dir, needle.as_bytes()), "{} is on disk", needle);
+    }
+
+    let wrong = run(
+        env!("CARGO_BIN_EXE_rocs"),
+        &dir,
+        &[("ROC_KEY_FILE", wrong_key.to_str().unwrap())],
+        &[],
+    );
+    assert!(!wrong.status.success());
+    let stderr = String::from_utf8_lossy(&wrong.stderr);
+    assert!(stderr.contains("wrong key or tampered data"), "{}", stderr);
+
+    let server = Server::start(&dir, &key_env);
+    let mut client = server.client();
+    for (key, value) in [
+        ("in-snapshot", "plaintext-one"),
+        ("in-wal", "plaintext-two"),
+    ] {
+        let reply = client.request(json!({"command": "FETCH", "key": key}));
+        assert_eq!(reply["Fetch"]["value"], json!({"Str": value}), "{}", reply);
+    }
+    server.shutdown();
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn a_key_file_is_refused_with_a_disk_engine() {
+    let dir = test_dir("encryption-disk");
+    let key = dir.join("key");
+    fs::write(&key, [7u8; 32]).unwrap();
+
+    for engine in ["btree", "lsm"] {
+        let env = [
+            ("ROC_ENGINE", engine),
+            ("ROC_KEY_FILE", key.to_str().unwrap()),
+        ];
+        let output = run(env!("CARGO_BIN_EXE_rocs"), &dir, &env, &[]);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("only works with the memory engine"),
+            "{}",
+            stderr
+        );
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
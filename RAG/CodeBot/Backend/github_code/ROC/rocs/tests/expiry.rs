@@ -0,0 +1,63 @@
+// ROC/rocs/tests/expiry.rs
+
+mod common;
+
+use common::{error, test_dir, Server};
+use serde_json::json;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn an_expiry_of_zero_is_refused() {
+    let dir = test_dir("expiry-zero");
+    let server = Server::start(&dir, &[]);
+    let mut client = server.client();
+
+    let reply = client.request(json!({"command": "STORE", "key": "a", "value": 1, "ex": 0}));
+    assert_eq!(
+        error(&reply),
+        Some("Expiry must be a positive number of seconds")
+    );
+    client.request(json!({"command": "STORE", "key": "a", "value": 1}));
+    let reply = client.request(json!({"command": "EXPIRE", "key": "a", "seconds": 0}));
+    assert!(error(&reply).is_some(), "{}", reply);
+    let reply = client.request(json!({"command": "TTL", "key": "a"}));
+    assert_eq!(reply["Ttl"]["ttl"], json!(null), "{}", reply);
+    server.shutdown();
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn the_sweeper_finds_expiring_keys_of_a_disk_engine_after_a_restart() {
+    let dir = test_dir("expiry-sweep");
+    let env = [("ROC_ENGINE", "lsm"), ("ROC_FSYNC", "always")];
+
+    let server = Server::start(&dir, &env);
+    let mut client = server.client();
+    for key in ["a", "b", "c"] {
+        client.request(json!({"command": "STORE", "key": key, "value": 1, "ex": 2}));
+    }
+    client.request(json!({"command": "STORE", "key": "d", "value": 1}));
+    client.request(json!({"command": "SNAPSHOT"}));
+    server.kill();
+
+    let server = Server::start(&dir, &env);
+    thread::sleep(Duration::from_millis(3500));
+    assert!(
+        server.log().contains("Expiry sweep removed 3 keys"),
+        "{}",
+        server.log()
+    );
+    let mut client = server.client();
+    let reply = client.request(json!({"command": "LIST"}));
+    assert_eq!(
+        reply["List"]["entries"],
+        json!([["d", {"Int": 1}]]),
+        "{}",
+        reply
+    );
+    server.shutdown();
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
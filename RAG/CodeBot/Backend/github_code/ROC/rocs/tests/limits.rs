@@ -0,0 +1,66 @@
+// ROC/rocs/tests/limits.rs
+
+mod common;
+
+use common::{error, test_dir, Server};
+use serde_json::json;
+
+#[test]
+fn a_key_too_long_for_the_btree_is_refused_and_the_server_restarts() {
+    let dir = test_dir("long-key");
+    let env = [("ROC_ENGINE", "btree"), ("ROC_FSYNC", "always")];
+
+    let server = Server::start(&dir, &env);
+    let mut client = server.client();
+    let long_key = "k".repeat(600);
+    let reply = client.request(json!({"command": "STORE", "key": long_key, "value": 1}));
+    assert_eq!(error(&reply), Some("Keys can be at most 512 bytes long"));
+    let reply = client.request(json!({"command": "STORE", "key": "a", "value": 2}));
+    assert_eq!(error(&reply), None, "{}", reply);
+    // the writer is still there
+    let reply = client.request(json!({"command": "STORE", "key": "b", "value": 3}));
+    assert_eq!(error(&reply), None, "{}", reply);
+    server.kill();
+
+    // the refused write never made it into the WAL, so the replay has nothing to trip over
+    let server = Server::start(&dir, &env);
+    let mut client = server.client();
+    for (key, value) in [("a", 2), ("b", 3)] {
+        let reply = client.request(json!({"command": "FETCH", "key": key}));
+        assert_eq!(reply["Fetch"]["value"], json!({"Int": value}), "{}", reply);
+    }
+    let reply = client.request(json!({"command": "FETCH", "key": long_key}));
+    assert!(error(&reply).is_some(), "{}", reply);
+    server.shutdown();
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn a_write_too_big_for_the_wal_is_refused() {
+    let dir = test_dir("big-write");
+    let env = [("ROC_FSYNC", "always")];
+
+    let server = Server::start(&dir, &env);
+    let mut client = server.client();
+    let big = "x".repeat(40 * 1024 * 1024);
+    let reply = client.request(json!({"command": "STORE", "key": "big", "value": big}));
+    assert!(
+        error(&reply).unwrap().starts_with("Write too large"),
+        "{}",
+        reply
+    );
+    let reply = client.request(json!({"command": "STORE", "key": "a", "value": 1}));
+    assert_eq!(error(&reply), None, "{}", reply);
+    server.kill();
+
+    let server = Server::start(&dir, &env);
+    let mut client = server.client();
+    let reply = client.request(json!({"command": "FETCH", "key": "a"}));
+    assert_eq!(reply["Fetch"]["value"], json!({"Int": 1}), "{}", reply);
+    let reply = client.request(json!({"command": "FETCH", "key": "big"}));
+    assert!(error(&reply).is_some(), "{}", reply);
+    server.shutdown();
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,124 @@
+// ROC/rocs/tests/pitr.rs
+
+mod common;
+
+use common::{run, test_dir, Server};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const ROCS: &str = env!("CARGO_BIN_EXE_rocs");
+
+/// Writes a history with two snapshots, returns a unix time in seconds between LSN 4 and 5
+///
+///  LSN 1  a = 1     snapshot up to 1
+///  LSN 2  a = 2
+///  LSN 3  b = 1     snapshot up to 3
+///  LSN 4  a = 3
+///  LSN 5  c = 1
+///  LSN 6  DELETE b
+fn write_history(dir: &Path, env: &[(&str, &str)]) -> f64 {
+    let server = Server::start(dir, env);
+    let mut client = server.client();
+    let mut request = |request: Value| {
+        let reply = client.request(request);
+        assert!(reply.get("ERR").is_none(), "{}", reply);
+    };
+
+    request(json!({"command": "STORE", "key": "a", "value": 1}));
+    request(json!({"command": "SNAPSHOT"}));
+    request(json!({"command": "STORE", "key": "a", "value": 2}));
+    request(json!({"command": "STORE", "key": "b", "value": 1}));
+    request(json!({"command": "SNAPSHOT"}));
+    request(json!({"command": "STORE", "key": "a", "value": 3}));
+
+    // the WAL keeps milliseconds, leave some room on both sides
+    thread::sleep(Duration::from_millis(200));
+    let between = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    thread::sleep(Duration::from_millis(200));
+
+    request(json!({"command": "STORE", "key": "c", "value": 1}));
+    request(json!({"command": "DELETE", "key": "b"}));
+    server.kill();
+    between
+}
+
+fn recover(dir: &Path, env: &[(&str, &str)], args: &[&str]) {
+    let args: Vec<&str> = ["recover"].iter().chain(args).copied().collect();
+    let output = run(ROCS, dir, env, &args);
+    assert!(
+        output.status.success(),
+        "{:?}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Starts the server, checks what it holds and that it takes new writes that last
+fn assert_recovered(dir: &Path, env: &[(&str, &str)], expected: Value) {
+    let server = Server::start(dir, env);
+    let mut client = server.client();
+    let reply = client.request(json!({"command": "LIST"}));
+    assert_eq!(reply["List"]["entries"], expected, "{}", reply);
+    client.request(json!({"command": "STORE", "key": "new", "value": 1}));
+    server.kill();
+
+    let server = Server::start(dir, env);
+    let reply = server
+        .client()
+        .request(json!({"command": "FETCH", "key": "new"}));
+    assert_eq!(reply["Fetch"]["value"], json!({"Int": 1}), "{}", reply);
+    server.shutdown();
+}
+
+fn recovers_to_a_point_in_time(engine: &str) {
+    let env = [
+        ("ROC_ENGINE", engine),
+        ("ROC_FSYNC", "always"),
+        ("ROC_SNAPSHOT_INTERVAL", "0"),
+    ];
+
+    // to an LSN, from the snapshot before it
+    let dir = test_dir(&format!("pitr-lsn-{}", engine));
+    write_history(&dir, &env);
+    recover(&dir, &env, &["--lsn", "2", "--exit"]);
+    assert_recovered(&dir, &env, json!([["a", {"Int": 2}]]));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // to a time
+    let dir = test_dir(&format!("pitr-time-{}", engine));
+    let between = write_history(&dir, &env);
+    recover(&dir, &env, &["--time", &between.to_string(), "--exit"]);
+    assert_recovered(&dir, &env, json!([["a", {"Int": 3}], ["b", {"Int": 1}]]));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // from an older snapshot than it has to
+    let dir = test_dir(&format!("pitr-snapshot-{}", engine));
+    write_history(&dir, &env);
+    recover(&dir, &env, &["--lsn", "5", "--snapshot", "1", "--exit"]);
+    assert_recovered(
+        &dir,
+        &env,
+        json!([["a", {"Int": 3}], ["b", {"Int": 1}], ["c", {"Int": 1}]]),
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn recovers_the_memory_engine_to_a_point_in_time() {
+    recovers_to_a_point_in_time("memory");
+}
+
+#[test]
+fn recovers_the_btree_engine_to_a_point_in_time() {
+    recovers_to_a_point_in_time("btree");
+}
+
+#[test]
+fn recovers_the_lsm_engine_to_a_point_in_time() {
+    recovers_to_a_point_in_time("lsm");
+}
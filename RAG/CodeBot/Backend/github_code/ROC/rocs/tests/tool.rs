@@ -0,0 +1,132 @@
+// ROC/rocs/tests/tool.rs
+
+mod common;
+
+use common::{run, test_dir, Server};
+use serde_json::json;
+use std::fs;
+use std::process::Output;
+
+const TOOL: &str = env!("CARGO_BIN_EXE_roc-tool");
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn checks_and_dumps_what_the_server_left_behind() {
+    let dir = test_dir("tool-check");
+    let env = [("ROC_FSYNC", "always")];
+
+    let server = Server::start(&dir, &env);
+    let mut client = server.client();
+    client.request(json!({"command": "STORE", "key": "a", "value": 1}));
+    client.request(json!({"command": "STORE", "key": "b", "value": "x"}));
+    let reply = client.request(json!({"command": "SNAPSHOT"}));
+    assert_eq!(reply["Snapshot"]["lsn"], json!(2), "{}", reply);
+    client.request(json!({"command": "STORE", "key": "c", "value": 3}));
+    server.kill();
+
+    let output = run(TOOL, &dir, &env, &["check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = stdout(&output);
+    assert!(report.contains("ok, 2 entries up to LSN 2"), "{}", report);
+    assert!(
+        report.contains("a restart carries on from the snapshot at LSN 2 and replays 1 records"),
+        "{}",
+        report
+    );
+
+    let snapshot = "../snaps/snapshot-00000000000000000002.json";
+    let output = run(TOOL, &dir, &env, &["snapshot", "dump", snapshot]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let dump = stdout(&output);
+    assert!(
+        dump.contains("a = 1") && dump.contains("b = \"x\""),
+        "{}",
+        dump
+    );
+    assert!(!dump.contains("c = 3"), "{}", dump);
+
+    let output = run(TOOL, &dir, &env, &["wal", "dump"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("\"key\":\"c\""),
+        "{}",
+        stdout(&output)
+    );
+
+    // a damaged snapshot is found and makes check fail
+    let path = dir.join("run").join(snapshot);
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 2;
+    data[last] ^= 0xff;
+    fs::write(&path, data).unwrap();
+    let output = run(TOOL, &dir, &env, &["snapshot", "verify", snapshot]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("damaged snapshot"),
+        "{}",
+        stderr(&output)
+    );
+    let output = run(TOOL, &dir, &env, &["check"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout(&output));
+
+    let output = run(TOOL, &dir, &env, &["snapshot"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn check_carries_on_from_a_disk_engine_like_a_restart() {
+    let dir = test_dir("tool-engine");
+    let env = [("ROC_ENGINE", "btree"), ("ROC_FSYNC", "always")];
+
+    let server = Server::start(&dir, &env);
+    let mut client = server.client();
+    client.request(json!({"command": "STORE", "key": "a", "value": 1}));
+    client.request(json!({"command": "SNAPSHOT"}));
+    client.request(json!({"command": "STORE", "key": "b", "value": 2}));
+    server.kill();
+
+    // without the snapshot, the WAL the snapshot covered is gone too -- the engine has it all
+    for file in fs::read_dir(dir.join("snaps")).unwrap() {
+        fs::remove_file(file.unwrap().path()).unwrap();
+    }
+
+    let output = run(TOOL, &dir, &env, &["check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = stdout(&output);
+    assert!(
+        report.contains("a restart carries on from the engine at LSN 1 and replays 1 records"),
+        "{}",
+        report
+    );
+
+    // and it does
+    let server = Server::start(&dir, &env);
+    let reply = server.client().request(json!({"command": "LIST"}));
+    assert_eq!(
+        reply["List"]["entries"],
+        json!([["a", {"Int": 1}], ["b", {"Int": 2}]]),
+        "{}",
+        reply
+    );
+    server.shutdown();
+
+    // under the memory engine a restart would need the snapshot, which SHUTDOWN took
+    let output = run(TOOL, &dir, &[], &["check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("a restart carries on from the snapshot at LSN 2"),
+        "{}",
+        stdout(&output)
+    );
+
+    let _ = fs::remove_dir_all(&dir);
+}
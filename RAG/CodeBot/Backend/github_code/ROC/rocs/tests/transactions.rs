@@ -0,0 +1,103 @@
+// ROC/rocs/tests/transactions.rs
+
+mod common;
+
+use common::{error, test_dir, Server};
+use serde_json::json;
+
+#[test]
+fn exec_fails_once_a_watched_key_changed() {
+    let dir = test_dir("tx-watch");
+    let server = Server::start(&dir, &[]);
+    let mut a = server.client();
+    let mut b = server.client();
+
+    a.request(json!({"command": "STORE", "key": "balance", "value": 10}));
+    a.request(json!({"command": "WATCH", "keys": ["balance"]}));
+    b.request(json!({"command": "STORE", "key": "balance", "value": 20}));
+    a.request(json!({"command": "MULTI"}));
+    a.request(json!({"command": "STORE", "key": "seen", "value": 10}));
+    let reply = a.request(json!({"command": "EXEC"}));
+    assert_eq!(
+        error(&reply),
+        Some("Transaction aborted: a watched key changed")
+    );
+    let reply = a.request(json!({"command": "FETCH", "key": "seen"}));
+    assert!(error(&reply).is_some(), "{}", reply);
+
+    // EXEC ended the watch, so the retry goes through -- as does an empty one that is watched
+    a.request(json!({"command": "WATCH", "keys": ["balance"]}));
+    a.request(json!({"command": "MULTI"}));
+    a.request(json!({"command": "STORE", "key": "seen", "value": 20}));
+    let reply = a.request(json!({"command": "EXEC"}));
+    assert_eq!(error(&reply), None, "{}", reply);
+    let reply = a.request(json!({"command": "FETCH", "key": "seen"}));
+    assert_eq!(reply["Fetch"]["value"], json!({"Int": 20}), "{}", reply);
+
+    // a key that is not there counts as well, once it shows up
+    a.request(json!({"command": "WATCH", "keys": ["lock"]}));
+    b.request(json!({"command": "STORE", "key": "lock", "value": 1}));
+    a.request(json!({"command": "MULTI"}));
+    let reply = a.request(json!({"command": "EXEC"}));
+    assert_eq!(
+        error(&reply),
+        Some("Transaction aborted: a watched key changed")
+    );
+    server.shutdown();
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn a_transaction_happens_all_at_once_or_not_at_all() {
+    let dir = test_dir("tx-atomic");
+    let env = [("ROC_ENGINE", "btree"), ("ROC_FSYNC", "always")];
+    let server = Server::start(&dir, &env);
+    let mut a = server.client();
+    let mut b = server.client();
+
+    // nothing is visible before EXEC
+    a.request(json!({"command": "MULTI"}));
+    a.request(json!({"command": "STORE", "key": "x", "value": 1}));
+    a.request(json!({"command": "STORE", "key": "y", "value": 2}));
+    let reply = b.request(json!({"command": "FETCH", "key": "x"}));
+    assert!(error(&reply).is_some(), "{}", reply);
+    let reply = a.request(json!({"command": "EXEC"}));
+    assert_eq!(error(&reply), None, "{}", reply);
+
+    // a request that could not be queued throws away the whole transaction
+    a.request(json!({"command": "MULTI"}));
+    a.request(json!({"command": "STORE", "key": "z", "value": 3}));
+    let reply = a.request(json!({"command": "FETCH", "key": "x"}));
+    assert!(error(&reply).is_some(), "{}", reply);
+    let reply = a.request(json!({"command": "EXEC"}));
+    assert_eq!(
+        error(&reply),
+        Some("Transaction discarded because of earlier errors")
+    );
+
+    // and so does a write that is refused when it is run, even after the ones before it
+    a.request(json!({"command": "MULTI"}));
+    a.request(json!({"command": "STORE", "key": "z", "value": 3}));
+    a.request(json!({"command": "STORE", "key": "s", "value": "text"}));
+    a.request(json!({"command": "INCR", "key": "s"}));
+    let reply = a.request(json!({"command": "EXEC"}));
+    assert_eq!(
+        error(&reply),
+        Some("Transaction aborted at command 3: Value is not an integer")
+    );
+
+    // what went through is still there after a crash, and nothing else
+    server.kill();
+    let server = Server::start(&dir, &env);
+    let reply = server.client().request(json!({"command": "LIST"}));
+    assert_eq!(
+        reply["List"]["entries"],
+        json!([["x", {"Int": 1}], ["y", {"Int": 2}]]),
+        "{}",
+        reply
+    );
+    server.shutdown();
+
+    let _ = std::fs::remove_dir_all(&dir);
+}